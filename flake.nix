@@ -36,7 +36,19 @@
         toolchain = p: p.rust-bin.stable.latest.default;
         craneLib = (crane.mkLib pkgs).overrideToolchain toolchain;
 
-        src = craneLib.cleanCargoSource ./.;
+        # Keeps the texthooker page embedded in the binary, which is not a Cargo
+        # source
+        cleanSource =
+          craneLib:
+          pkgs.lib.cleanSourceWith {
+            src = ./.;
+            name = "source";
+            filter =
+              path: type:
+              (pkgs.lib.hasSuffix ".html" path) || (craneLib.filterCargoSources path type);
+          };
+
+        src = cleanSource craneLib;
 
         commonArgs = {
           inherit src;
@@ -63,7 +75,7 @@
               };
             craneLib = (crane.mkLib pkgs).overrideToolchain toolchain;
 
-            src = craneLib.cleanCargoSource ./.;
+            src = cleanSource craneLib;
 
             commonArgs = {
               inherit src;
@@ -121,7 +133,7 @@
               };
             craneLib = (crane.mkLib pkgs).overrideToolchain toolchain;
 
-            src = craneLib.cleanCargoSource ./.;
+            src = cleanSource craneLib;
 
             commonArgs = {
               inherit src;
@@ -195,7 +207,7 @@
               };
             craneLib = (crane.mkLib pkgs).overrideToolchain toolchain;
 
-            src = craneLib.cleanCargoSource ./.;
+            src = cleanSource craneLib;
 
             # We need to vendor our dependencies since we're recompiling rust
             # std
@@ -309,6 +321,23 @@
             }
           );
 
+          # The `tls` feature is not enabled by the checks above
+          clippy_tls = craneLib.cargoClippy (
+            commonArgs
+            // {
+              inherit cargoArtifacts;
+              cargoClippyExtraArgs = "--all-targets --all-features -- --deny warnings";
+            }
+          );
+
+          test_tls = craneLib.cargoTest (
+            commonArgs
+            // {
+              inherit cargoArtifacts;
+              cargoTestExtraArgs = "--all-features";
+            }
+          );
+
           format = craneLib.cargoFmt {
             inherit src;
           };
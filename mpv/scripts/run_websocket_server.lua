@@ -51,13 +51,40 @@ if platform == "windows" then
   mpv_socket = "\\\\.\\pipe" .. mpv_socket:gsub("/", "\\")
 end
 
+-- See `Error::exit_code` in src/error.rs
+local exit_code_descriptions = {
+  [2] = "invalid arguments or configuration file",
+  [3] = "failed to start the WebSocket server",
+  [4] = "mpv socket error",
+  [5] = "WebSocket server error",
+  [6] = "failed to poll for events",
+  [7] = "TLS certificate error",
+  [101] = "unexpected error",
+}
+
+local function on_websocket_exit(_, result, _)
+  if result == nil or result.killed_by_us or result.status == 0 then
+    return
+  end
+
+  initialised_websocket = nil
+
+  local message = exit_code_descriptions[result.status] or ("exited with status " .. tostring(result.status))
+  -- mpv_websocket logs the reason it stopped as the last line of stderr
+  local reason = (result.stderr or ""):match("ERROR[^:\n]*:%s*([^\n]*)\n*$")
+  if reason then
+    message = reason
+  end
+
+  mp.msg.error("mpv_websocket stopped: " .. message)
+  mp.osd_message("mpv_websocket stopped: " .. message, 5)
+end
+
 local function start_websocket()
   local args = {
     mpv_websocket_path,
     "-m",
     mpv_socket,
-    "-w",
-    "6677",
   }
 
   if use_secondary_subs then
@@ -70,7 +97,7 @@ local function start_websocket()
     capture_stdout = true,
     capture_stderr = true,
     args = args,
-  })
+  }, on_websocket_exit)
 end
 
 local function end_websocket()
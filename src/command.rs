@@ -0,0 +1,308 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::event::Event;
+
+/// Commands that WebSocket clients are allowed to send to mpv. Anything that
+/// could affect more than playback and subtitles, such as `quit`, `run` or
+/// `loadfile`, is rejected.
+const ALLOWED_COMMANDS: &[&str] = &[
+    "seek",
+    "revert-seek",
+    "sub-seek",
+    "sub-step",
+    "frame-step",
+    "frame-back-step",
+    "get_property",
+    "set_property",
+    "cycle",
+    "add",
+];
+
+/// Properties that can be changed with `set_property`, `cycle` and `add`
+const WRITABLE_PROPERTIES: &[&str] = &[
+    "pause",
+    "time-pos",
+    "speed",
+    "sub-delay",
+    "secondary-sub-delay",
+    "sub-visibility",
+    "secondary-sub-visibility",
+    "ab-loop-a",
+    "ab-loop-b",
+];
+
+/// Properties that can be read with `get_property`, in addition to the
+/// writable ones. These are limited to playback, subtitles and the metadata
+/// that is already broadcast, so that clients cannot read mpv's configuration
+/// or environment.
+const READABLE_PROPERTIES: &[&str] = &[
+    "sub-text",
+    "secondary-sub-text",
+    "sub-start",
+    "sub-end",
+    "secondary-sub-start",
+    "secondary-sub-end",
+    "playback-time",
+    "percent-pos",
+    "time-remaining",
+    "duration",
+    "chapter",
+    "media-title",
+    "filename",
+    "path",
+    "current-tracks/sub",
+];
+
+/// Identifies the WebSocket client that sent a command, so that the reply from
+/// mpv is only sent back to that client
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ClientId(pub usize);
+
+#[derive(Debug)]
+pub enum CommandError {
+    InvalidRequest(serde_json::Error),
+    MissingName,
+    NotAllowed(String),
+    PropertyNotAllowed(String),
+    PropertyNotReadable(String),
+    InvalidArgument,
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::InvalidRequest(error) => write!(f, "invalid request: {error}"),
+            CommandError::MissingName => write!(f, "the command name is missing"),
+            CommandError::NotAllowed(name) => write!(f, "command `{name}` is not allowed"),
+            CommandError::PropertyNotAllowed(name) => {
+                write!(f, "property `{name}` cannot be changed")
+            }
+            CommandError::PropertyNotReadable(name) => {
+                write!(f, "property `{name}` cannot be read")
+            }
+            CommandError::InvalidArgument => {
+                write!(f, "command arguments must be strings, numbers or booleans")
+            }
+        }
+    }
+}
+
+impl Error for CommandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CommandError::InvalidRequest(error) => Some(error),
+            CommandError::MissingName
+            | CommandError::NotAllowed(_)
+            | CommandError::PropertyNotAllowed(_)
+            | CommandError::PropertyNotReadable(_)
+            | CommandError::InvalidArgument => None,
+        }
+    }
+}
+
+// Example request:
+// {"command":["sub-seek",-1],"request_id":1}
+#[derive(Deserialize)]
+struct Request {
+    command: Vec<Value>,
+    #[serde(default)]
+    request_id: Value,
+}
+
+/// A validated command from a WebSocket client, to be forwarded to mpv
+pub struct Command {
+    pub client: ClientId,
+    pub request_id: Value,
+    pub command: Vec<Value>,
+}
+
+impl Command {
+    /// Parses and validates a command sent by a WebSocket client. On failure,
+    /// the reply to send back to the client is returned instead
+    pub fn parse(client: ClientId, text: &str) -> Result<Self, Event> {
+        let request = serde_json::from_str::<Request>(text).map_err(|e| Event::CommandReply {
+            client,
+            request_id: Value::Null,
+            error: CommandError::InvalidRequest(e).to_string(),
+            data: Value::Null,
+        })?;
+
+        let command = Self {
+            client,
+            request_id: request.request_id,
+            command: request.command,
+        };
+        match command.validate() {
+            Ok(()) => Ok(command),
+            Err(e) => Err(command.reject(e.to_string())),
+        }
+    }
+
+    fn validate(&self) -> Result<(), CommandError> {
+        let Some(Value::String(name)) = self.command.first() else {
+            return Err(CommandError::MissingName);
+        };
+        if !ALLOWED_COMMANDS.contains(&name.as_str()) {
+            return Err(CommandError::NotAllowed(name.clone()));
+        }
+
+        if !self.command[1..].iter().all(|argument| {
+            matches!(
+                argument,
+                Value::String(_) | Value::Number(_) | Value::Bool(_)
+            )
+        }) {
+            return Err(CommandError::InvalidArgument);
+        }
+
+        match name.as_str() {
+            "set_property" | "cycle" | "add" => {
+                let property = self.property()?;
+                if !WRITABLE_PROPERTIES.contains(&property) {
+                    return Err(CommandError::PropertyNotAllowed(property.to_owned()));
+                }
+            }
+            "get_property" => {
+                let property = self.property()?;
+                if !WRITABLE_PROPERTIES.contains(&property)
+                    && !READABLE_PROPERTIES.contains(&property)
+                {
+                    return Err(CommandError::PropertyNotReadable(property.to_owned()));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// The property that the command reads or changes
+    fn property(&self) -> Result<&str, CommandError> {
+        match self.command.get(1) {
+            Some(Value::String(property)) => Ok(property),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+
+    /// Creates the reply for a command that could not be sent to mpv
+    pub fn reject(self, error: impl Into<String>) -> Event {
+        Event::CommandReply {
+            client: self.client,
+            request_id: self.request_id,
+            error: error.into(),
+            data: Value::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn validate(command: Value) -> Result<(), CommandError> {
+        Command {
+            client: ClientId(0),
+            request_id: Value::Null,
+            command: serde_json::from_value(command).unwrap(),
+        }
+        .validate()
+    }
+
+    #[test]
+    fn validate_accepts_allowed_commands() {
+        for command in [
+            json!(["seek", 5, "relative"]),
+            json!(["revert-seek"]),
+            json!(["sub-seek", -1]),
+            json!(["sub-step", 1, "primary"]),
+            json!(["frame-step"]),
+            json!(["frame-back-step"]),
+            json!(["get_property", "sub-text"]),
+            json!(["get_property", "pause"]),
+            json!(["set_property", "pause", true]),
+            json!(["cycle", "sub-visibility"]),
+            json!(["add", "sub-delay", 0.1]),
+        ] {
+            assert!(validate(command.clone()).is_ok(), "{command}");
+        }
+    }
+
+    #[test]
+    fn validate_rejects_other_commands() {
+        for command in [
+            json!(["quit"]),
+            json!(["run", "sh", "-c", "true"]),
+            json!(["loadfile", "/etc/passwd"]),
+            json!(["SEEK", 5]),
+        ] {
+            assert!(
+                matches!(validate(command.clone()), Err(CommandError::NotAllowed(_))),
+                "{command}"
+            );
+        }
+        assert!(matches!(
+            validate(json!([])),
+            Err(CommandError::MissingName)
+        ));
+        assert!(matches!(
+            validate(json!([1, "seek"])),
+            Err(CommandError::MissingName)
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_unlisted_properties() {
+        for command in [
+            json!(["set_property", "sub-text", "spoofed"]),
+            json!(["cycle", "fullscreen"]),
+            json!(["add", "volume", 10]),
+        ] {
+            assert!(
+                matches!(
+                    validate(command.clone()),
+                    Err(CommandError::PropertyNotAllowed(_))
+                ),
+                "{command}"
+            );
+        }
+        for command in [
+            json!(["get_property", "input-ipc-server"]),
+            json!(["get_property", "user-data"]),
+            json!(["get_property", "env"]),
+        ] {
+            assert!(
+                matches!(
+                    validate(command.clone()),
+                    Err(CommandError::PropertyNotReadable(_))
+                ),
+                "{command}"
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_non_scalar_arguments() {
+        for command in [
+            json!(["seek", [5], "relative"]),
+            json!(["seek", {"target": 5}]),
+            json!(["seek", null]),
+            json!(["set_property", "pause", {"value": true}]),
+            json!(["get_property"]),
+            json!(["set_property", 1, true]),
+        ] {
+            assert!(
+                matches!(
+                    validate(command.clone()),
+                    Err(CommandError::InvalidArgument)
+                ),
+                "{command}"
+            );
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use mpv_websocket::event::OutputFormat;
+use mpv_websocket::filter::Filter;
+use mpv_websocket::mpv::InvalidUtf8;
+use mpv_websocket::websocket::{self, SlowClientPolicy, Subscription};
+use serde::{Deserialize, Serialize};
+use tracing_appender::rolling::Rotation;
+use tracing_subscriber::filter::LevelFilter;
+
+/// The configuration file used when `--config` is not given, looked up next to
+/// the executable
+const CONFIG_FILE_NAME: &str = "mpv_websocket.toml";
+
+// Example configuration file:
+//
+// mpvsocket_path = "/tmp/mpv-socket"
+// websocket_server_port = 6677
+// subtitles = "both"
+// output_format = "json"
+//
+// [filters]
+// skip_empty = true
+//
+// [logging]
+// level = "info"
+// rotation = "never"
+//
+// [tls]
+// self_signed = true
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub mpvsocket_path: Option<PathBuf>,
+    pub websocket_server_bind_address: IpAddr,
+    pub websocket_server_port: u16,
+    /// The subtitles sent to clients that do not use the `subtitles` query
+    /// parameter
+    pub subtitles: Subscription,
+    pub output_format: OutputFormat,
+    pub history_size: usize,
+    /// The shared secret clients must send to connect, see
+    /// `Server::auth_token`. Anyone can connect if unset
+    #[serde(serialize_with = "redact")]
+    pub auth_token: Option<String>,
+    /// The origins of the pages allowed to connect, see
+    /// `Server::allowed_origins`. Every origin is allowed if empty
+    pub allowed_origins: Vec<String>,
+    /// Directory whose files are served over HTTP instead of the bundled
+    /// texthooker page
+    pub static_dir: Option<PathBuf>,
+    /// Seconds a WebSocket client can stay silent before it is pinged, or 0
+    /// to never ping clients
+    pub ping_interval: u64,
+    /// Seconds a WebSocket client has to answer a ping before it is
+    /// disconnected
+    pub pong_timeout: u64,
+    /// Seconds a connection has to finish its TLS handshake and send its
+    /// request
+    pub request_timeout: u64,
+    /// Messages queued for a WebSocket client that does not keep up, beyond
+    /// which `slow_client_policy` applies
+    pub max_queued_messages: NonZeroUsize,
+    pub slow_client_policy: SlowClientPolicy,
+    /// What happens to mpv responses that are not valid UTF-8
+    pub invalid_utf8: InvalidUtf8,
+    /// Decode mpv responses that are not valid UTF-8 as EUC-JP or Shift_JIS
+    /// if possible
+    pub charset_fallback: bool,
+    /// Other mpv properties whose changes are broadcast, for example
+    /// `media-title` or `pause`
+    pub observe: Vec<String>,
+    pub filters: Filter,
+    pub logging: Logging,
+    #[cfg(feature = "tls")]
+    pub tls: Tls,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mpvsocket_path: None,
+            websocket_server_bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            websocket_server_port: 6677,
+            subtitles: Subscription::Primary,
+            output_format: OutputFormat::Plain,
+            history_size: 100,
+            auth_token: None,
+            allowed_origins: Vec::new(),
+            static_dir: None,
+            ping_interval: 30,
+            pong_timeout: 10,
+            request_timeout: 10,
+            max_queued_messages: NonZeroUsize::new(1000).expect("1000 should not be zero"),
+            slow_client_policy: SlowClientPolicy::DropOldest,
+            invalid_utf8: InvalidUtf8::Replace,
+            charset_fallback: false,
+            observe: Vec::new(),
+            filters: Filter::default(),
+            logging: Logging::default(),
+            #[cfg(feature = "tls")]
+            tls: Tls::default(),
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    pub level: LogLevel,
+    /// Defaults to `logs` next to the executable
+    pub directory: Option<PathBuf>,
+    pub rotation: LogRotation,
+    /// The oldest log files are deleted beyond this number. All of them are
+    /// kept by default
+    pub max_files: Option<NonZeroUsize>,
+    pub format: LogFormat,
+}
+
+/// TLS is used when a certificate is set or `self_signed` is enabled
+#[cfg(feature = "tls")]
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    /// PEM encoded certificate chain. Defaults to `tls/certificate.pem` next
+    /// to the executable
+    pub certificate: Option<PathBuf>,
+    /// PEM encoded private key. Defaults to `tls/key.pem` next to the
+    /// executable
+    pub key: Option<PathBuf>,
+    /// Generate a self-signed certificate if the certificate or the key does
+    /// not exist
+    pub self_signed: bool,
+}
+
+#[cfg(feature = "tls")]
+impl Tls {
+    pub fn is_enabled(&self) -> bool {
+        self.self_signed || self.certificate.is_some() || self.key.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    #[default]
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// Start a new log file every hour
+    Hourly,
+    /// Start a new log file every day
+    #[default]
+    Daily,
+    /// Always log to the same file
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(value: LogRotation) -> Self {
+        match value {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// A number of seconds outside of the accepted range, whether it comes
+    /// from the configuration file or the arguments
+    OutOfRange {
+        name: &'static str,
+        value: u64,
+        min: u64,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(
+                    f,
+                    "failed to read config file `{}`: {source}",
+                    path.display()
+                )
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid config file `{}`: {source}", path.display())
+            }
+            ConfigError::OutOfRange { name, value, min } => {
+                write!(
+                    f,
+                    "`{name}` must be between {min} and {} seconds, got {value}",
+                    websocket::MAX_TIMEOUT.as_secs()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
+            ConfigError::OutOfRange { .. } => None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration file at `path`. Without a path, the
+    /// configuration file next to the executable is loaded if it exists,
+    /// otherwise the default configuration is used
+    pub fn load(path: Option<&Path>, executable_dir: &Path) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => {
+                let path = executable_dir.join(CONFIG_FILE_NAME);
+                if !path.is_file() {
+                    return Ok(Self::default());
+                }
+                path
+            }
+        };
+
+        let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Read {
+            path: path.clone(),
+            source: e,
+        })?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse { path, source: e })
+    }
+
+    /// Checks the values that can be out of range once the arguments have been
+    /// applied
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (name, value, min) in [
+            ("ping_interval", self.ping_interval, 0),
+            ("pong_timeout", self.pong_timeout, 1),
+            ("request_timeout", self.request_timeout, 1),
+        ] {
+            if !(min..=websocket::MAX_TIMEOUT.as_secs()).contains(&value) {
+                return Err(ConfigError::OutOfRange { name, value, min });
+            }
+        }
+        Ok(())
+    }
+
+    /// The configuration as TOML, with the secrets redacted
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config should be serializable to TOML")
+    }
+}
+
+/// Serializes a secret without revealing it, so that `--print-config` can be
+/// shared
+fn redact<S: serde::Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_some("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::mpv::SocketError;
+#[cfg(feature = "tls")]
+use crate::tls::TlsError;
+use crate::websocket::WebSocketError;
+
+/// An error that stops mpv_websocket.
+///
+/// Each error maps to a distinct process exit code, see [`Error::exit_code`]
+#[derive(Debug)]
+pub enum Error {
+    /// The WebSocket server could not listen on its address, usually because
+    /// another program is already using the port
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    /// A poll instance could not be created or a source could not be
+    /// registered with it
+    Poll(io::Error),
+    /// The connection to mpv failed in a way that reconnecting cannot fix
+    Mpv(SocketError),
+    /// The WebSocket server failed in a way that it cannot recover from
+    WebSocket(WebSocketError),
+    /// The WebSocket server thread stopped, so events can no longer be
+    /// broadcast
+    ServerStopped,
+    /// The TLS certificate could not be loaded or generated
+    #[cfg(feature = "tls")]
+    Tls(TlsError),
+}
+
+impl Error {
+    /// The exit code of the process when it stops because of this error:
+    ///
+    /// | Code | Error                                          |
+    /// |------|------------------------------------------------|
+    /// | 2    | Invalid command line arguments or config file  |
+    /// | 3    | [`Error::Bind`]                                |
+    /// | 4    | [`Error::Mpv`]                                 |
+    /// | 5    | [`Error::WebSocket`], [`Error::ServerStopped`] |
+    /// | 6    | [`Error::Poll`]                                |
+    /// | 7    | `Error::Tls`, with the `tls` feature           |
+    /// | 101  | Unexpected internal error (panic)              |
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Bind { .. } => 3,
+            Error::Mpv(_) => 4,
+            Error::WebSocket(_) | Error::ServerStopped => 5,
+            Error::Poll(_) => 6,
+            #[cfg(feature = "tls")]
+            Error::Tls(_) => 7,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Bind { address, source } => write!(
+                f,
+                "failed to start the WebSocket server at `{address}`: {source}. \
+                 Is another instance of mpv_websocket already running?"
+            ),
+            Error::Poll(error) => write!(f, "failed to poll for events: {error}"),
+            Error::Mpv(error) => write!(f, "mpv socket error: {error}"),
+            Error::WebSocket(error) => write!(f, "WebSocket server error: {error}"),
+            Error::ServerStopped => write!(f, "the WebSocket server stopped unexpectedly"),
+            #[cfg(feature = "tls")]
+            Error::Tls(error) => write!(f, "TLS error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { source, .. } => Some(source),
+            Error::Poll(error) => Some(error),
+            Error::Mpv(error) => Some(error),
+            Error::WebSocket(error) => Some(error),
+            Error::ServerStopped => None,
+            #[cfg(feature = "tls")]
+            Error::Tls(error) => Some(error),
+        }
+    }
+}
+
+impl From<SocketError> for Error {
+    fn from(value: SocketError) -> Self {
+        match value {
+            SocketError::ServerStopped => Self::ServerStopped,
+            error => Self::Mpv(error),
+        }
+    }
+}
+
+impl From<WebSocketError> for Error {
+    fn from(value: WebSocketError) -> Self {
+        Self::WebSocket(value)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<TlsError> for Error {
+    fn from(value: TlsError) -> Self {
+        Self::Tls(value)
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::command::ClientId;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Send the subtitle text as is, compatible with texthooker pages
+    Plain,
+    /// Wrap every message in a JSON object with its type, timing and sequence
+    /// number
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Property {
+    #[serde(rename = "sub-text")]
+    SubText,
+    #[serde(rename = "secondary-sub-text")]
+    SecondarySubText,
+}
+
+/// A message broadcast to the WebSocket clients
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    Subtitle {
+        property: Property,
+        text: String,
+        sub_start: Option<f64>,
+        sub_end: Option<f64>,
+        time_pos: Option<f64>,
+    },
+    MpvConnected,
+    MpvDisconnected,
+    /// A new value of a property observed with
+    /// [`crate::mpv::Client::observe`]. `data` is `null` when mpv has no
+    /// value for the property, for example `media-title` when nothing is
+    /// playing
+    PropertyChange {
+        name: String,
+        data: Value,
+    },
+    /// A playback event of mpv, such as a new file being loaded
+    Lifecycle(LifecycleEvent),
+    /// What is playing, sent when a file is loaded and first to the clients
+    /// connecting while it plays
+    Metadata(MediaMetadata),
+    /// The reply to a command, only sent to the client that sent the command
+    CommandReply {
+        #[serde(skip)]
+        client: ClientId,
+        request_id: Value,
+        error: String,
+        data: Value,
+    },
+}
+
+/// The events of mpv's event stream that are forwarded to the clients, named
+/// and shaped as mpv sends them
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum LifecycleEvent {
+    FileLoaded,
+    EndFile {
+        #[serde(default)]
+        reason: EndFileReason,
+    },
+    Seek,
+    PlaybackRestart,
+    Pause,
+    Unpause,
+    /// mpv is quitting and closes the connection after this event
+    Shutdown,
+}
+
+/// The file mpv is playing. Each field is `None` when mpv does not know it,
+/// for example the duration of a live stream
+#[derive(Clone, Default, Serialize)]
+pub struct MediaMetadata {
+    pub media_title: Option<String>,
+    pub path: Option<String>,
+    pub filename: Option<String>,
+    /// In seconds
+    pub duration: Option<f64>,
+    /// The selected subtitle track, as mpv describes it in its
+    /// `current-tracks/sub` property
+    pub subtitle_track: Option<Value>,
+}
+
+/// Why mpv stopped playing a file
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndFileReason {
+    /// The end of the file was reached
+    Eof,
+    /// Playback was stopped, for example to play the next file of the playlist
+    Stop,
+    Quit,
+    /// The file could not be played
+    Error,
+    /// The file was a playlist, whose entries are played instead
+    Redirect,
+    /// A reason added by a newer version of mpv
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+// Kept for the plain output format, which predates the JSON envelope
+const MPV_DISCONNECTED_NOTIFICATION: &str = "{\"event\":\"mpv-disconnected\"}";
+const MPV_CONNECTED_NOTIFICATION: &str = "{\"event\":\"mpv-connected\"}";
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    timestamp: u64,
+    seq: u64,
+}
+
+impl Event {
+    /// Renders the event as the text sent to the clients. `seq` is the position
+    /// of the event in the broadcast sequence
+    pub fn render(&self, format: OutputFormat, seq: u64) -> Arc<str> {
+        if let Event::CommandReply { .. } = self {
+            // Clients sending commands expect JSON replies regardless of the
+            // output format. Replies are not part of the broadcast sequence
+            return serde_json::to_string(self)
+                .expect("command reply should be serializable to JSON")
+                .into();
+        }
+
+        match format {
+            OutputFormat::Plain => match self {
+                Event::Subtitle { text, .. } => text.as_str().into(),
+                Event::MpvConnected => MPV_CONNECTED_NOTIFICATION.into(),
+                Event::MpvDisconnected => MPV_DISCONNECTED_NOTIFICATION.into(),
+                // Sent as JSON like the notifications above, so that
+                // texthooker pages can tell them apart from subtitles
+                Event::PropertyChange { name, data } => serde_json::json!({
+                    "event": "property-change",
+                    "name": name,
+                    "data": data,
+                })
+                .to_string()
+                .into(),
+                // The same JSON as mpv sends
+                Event::Lifecycle(event) => serde_json::to_string(event)
+                    .expect("lifecycle event should be serializable to JSON")
+                    .into(),
+                Event::Metadata(metadata) => {
+                    let mut message = serde_json::to_value(metadata)
+                        .expect("metadata should be serializable to JSON");
+                    message["event"] = "metadata".into();
+                    message.to_string().into()
+                }
+                Event::CommandReply { .. } => unreachable!("command replies are rendered as JSON"),
+            },
+            OutputFormat::Json => {
+                // Milliseconds since the Unix epoch, which is what JavaScript
+                // clients expect for `new Date(timestamp)`
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_millis() as u64);
+                serde_json::to_string(&Envelope {
+                    event: self,
+                    timestamp,
+                    seq,
+                })
+                .expect("event should be serializable to JSON")
+                .into()
+            }
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+/// Changes or drops subtitles before they are broadcast. The default filter
+/// keeps every subtitle as is
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// Drop empty subtitles, which mpv sends when a subtitle disappears
+    pub skip_empty: bool,
+    /// Join the lines of multi-line subtitles into a single line, without a
+    /// separator as is usual for Japanese text
+    pub join_lines: bool,
+    /// Drop subtitles containing any of these strings, for example the music
+    /// notes around song lyrics
+    pub ignore: Vec<String>,
+}
+
+impl Filter {
+    /// Returns the text to broadcast, or `None` if the subtitle is dropped
+    pub fn apply(&self, text: &str) -> Option<String> {
+        if self
+            .ignore
+            .iter()
+            .any(|ignored| text.contains(ignored.as_str()))
+        {
+            return None;
+        }
+
+        let text = if self.join_lines {
+            text.lines().map(str::trim).collect::<String>()
+        } else {
+            text.to_owned()
+        };
+
+        if self.skip_empty && text.trim().is_empty() {
+            return None;
+        }
+
+        Some(text)
+    }
+}
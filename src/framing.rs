@@ -0,0 +1,168 @@
+//! Splits the byte stream of mpv's JSON IPC into its newline-delimited
+//! messages
+
+use std::fmt::Display;
+
+/// A line longer than the maximum, which is skipped
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LineTooLong {
+    max_line_length: usize,
+}
+
+impl Display for LineTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line is longer than {} bytes", self.max_line_length)
+    }
+}
+
+/// Reassembles lines from the chunks read from a stream, keeping the partial
+/// line at the end of a chunk until the rest of it is read
+pub(crate) struct LineDecoder {
+    partial_line: Vec<u8>,
+    max_line_length: usize,
+    /// Whether the rest of a line that is too long is being skipped
+    skipping: bool,
+}
+
+impl LineDecoder {
+    pub(crate) fn new(max_line_length: usize) -> Self {
+        Self {
+            partial_line: Vec::new(),
+            max_line_length,
+            skipping: false,
+        }
+    }
+
+    /// Returns the lines completed by `chunk`, without their line ending. A
+    /// line that is too long is reported once, as soon as it exceeds the
+    /// maximum, and the rest of it is skipped
+    pub(crate) fn decode(&mut self, mut chunk: &[u8]) -> Vec<Result<Vec<u8>, LineTooLong>> {
+        let mut lines = Vec::new();
+        while !chunk.is_empty() {
+            let (part, complete) = match chunk.iter().position(|&byte| byte == b'\n') {
+                Some(end) => {
+                    let part = &chunk[..end];
+                    chunk = &chunk[end + 1..];
+                    (part, true)
+                }
+                None => (std::mem::take(&mut chunk), false),
+            };
+
+            if self.skipping {
+                self.skipping = !complete;
+                continue;
+            }
+            if self.partial_line.len() + part.len() > self.max_line_length {
+                lines.push(Err(LineTooLong {
+                    max_line_length: self.max_line_length,
+                }));
+                self.partial_line.clear();
+                self.skipping = !complete;
+                continue;
+            }
+
+            self.partial_line.extend_from_slice(part);
+            if complete {
+                let mut line = std::mem::take(&mut self.partial_line);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                lines.push(Ok(line));
+            }
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSES: &[u8] = b"{\"event\":\"property-change\",\"id\":1,\"name\":\"sub-text\",\"data\":\"\xe3\x81\x93\xe3\x82\x93\xe3\x81\xab\xe3\x81\xa1\xe3\x81\xaf\"}\n\
+        {\"data\":12.345,\"request_id\":1,\"error\":\"success\"}\r\n\
+        \n\
+        {\"event\":\"property-change\",\"id\":2,\"name\":\"secondary-sub-text\",\"data\":\"hello\\nworld\"}\n";
+
+    fn expected_lines() -> Vec<Result<Vec<u8>, LineTooLong>> {
+        RESPONSES
+            .split(|&byte| byte == b'\n')
+            .take(4)
+            .map(|line| Ok(line.strip_suffix(b"\r").unwrap_or(line).to_vec()))
+            .collect()
+    }
+
+    fn decode_chunks<'a>(
+        decoder: &mut LineDecoder,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<Result<Vec<u8>, LineTooLong>> {
+        chunks
+            .into_iter()
+            .flat_map(|chunk| decoder.decode(chunk))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_chunks_of_every_size() {
+        for size in 1..=RESPONSES.len() {
+            let mut decoder = LineDecoder::new(1024);
+            assert_eq!(
+                decode_chunks(&mut decoder, RESPONSES.chunks(size)),
+                expected_lines(),
+                "chunks of {size} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_every_split_into_three_chunks() {
+        for first in 0..=RESPONSES.len() {
+            for second in first..=RESPONSES.len() {
+                let mut decoder = LineDecoder::new(1024);
+                let chunks = [
+                    &RESPONSES[..first],
+                    &RESPONSES[first..second],
+                    &RESPONSES[second..],
+                ];
+                assert_eq!(
+                    decode_chunks(&mut decoder, chunks),
+                    expected_lines(),
+                    "split at {first} and {second}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_partial_line_until_it_is_complete() {
+        let mut decoder = LineDecoder::new(1024);
+        assert_eq!(decoder.decode(b"{\"data\":"), Vec::new());
+        assert_eq!(
+            decoder.decode(b"1}\n{\"da"),
+            vec![Ok(b"{\"data\":1}".to_vec())]
+        );
+        assert_eq!(
+            decoder.decode(b"ta\":2}\n"),
+            vec![Ok(b"{\"data\":2}".to_vec())]
+        );
+    }
+
+    #[test]
+    fn skips_lines_that_are_too_long() {
+        let too_long = Err(LineTooLong { max_line_length: 4 });
+        for size in 1..=8 {
+            let mut decoder = LineDecoder::new(4);
+            let input = b"1234\n123456789\nabc\n12345";
+            assert_eq!(
+                decode_chunks(&mut decoder, input.chunks(size)),
+                vec![
+                    Ok(b"1234".to_vec()),
+                    too_long.clone(),
+                    Ok(b"abc".to_vec()),
+                    too_long.clone(),
+                ],
+                "chunks of {size} bytes"
+            );
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! Plain HTTP requests served on the WebSocket port, for monitoring, for
+//! scripts probing whether the server is running and for the texthooker page
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use tracing::warn;
+use tungstenite::handshake::server::Request;
+use tungstenite::http::{Method, StatusCode};
+
+use crate::mio_channel::Stats;
+
+/// Requests whose head is larger than this are rejected
+pub(crate) const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+
+const MAX_HEADERS: usize = 64;
+
+/// A minimal texthooker page served at `/` unless a static directory is set
+const TEXTHOOKER_PAGE: &str = include_str!("texthooker.html");
+
+/// The start line and the headers of an HTTP request
+pub(crate) struct RequestHead {
+    pub(crate) request: Request,
+    /// Whether the client asks to upgrade the connection to a WebSocket
+    pub(crate) is_websocket_upgrade: bool,
+}
+
+/// Parses the head of the request received so far. Returns `None` if the head
+/// is not complete yet
+pub(crate) fn parse_request_head(buffer: &[u8]) -> io::Result<Option<RequestHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(buffer) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) if buffer.len() < MAX_REQUEST_HEAD_SIZE => return Ok(None),
+        Ok(httparse::Status::Partial) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head is too large",
+            ))
+        }
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+
+    let is_websocket_upgrade = request.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("upgrade")
+            && String::from_utf8_lossy(header.value)
+                .split(',')
+                .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+    });
+    let mut builder = Request::builder()
+        .method(request.method.unwrap_or_default())
+        .uri(request.path.unwrap_or("/"));
+    for header in request.headers.iter() {
+        builder = builder.header(header.name, header.value);
+    }
+    let request = builder
+        .body(())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(Some(RequestHead {
+        request,
+        is_websocket_upgrade,
+    }))
+}
+
+/// The state of the server reported by `/status`
+pub(crate) struct ServerStatus {
+    pub(crate) mpv_connected: bool,
+    /// The number of connected WebSocket clients
+    pub(crate) clients: usize,
+    /// The number of messages sent to all clients
+    pub(crate) messages_sent: u64,
+    pub(crate) client_queues: Vec<ClientQueueStatus>,
+    /// The counters of the events sent by the mpv connection
+    pub(crate) event_queue: Stats,
+    started: Instant,
+}
+
+/// The send queue of a WebSocket client
+pub(crate) struct ClientQueueStatus {
+    /// Identifies the connection of the client
+    pub(crate) id: usize,
+    /// The number of messages waiting to be sent
+    pub(crate) queued: usize,
+    /// The number of messages dropped because the client did not keep up
+    pub(crate) dropped: u64,
+}
+
+impl ServerStatus {
+    pub(crate) fn new() -> Self {
+        Self {
+            mpv_connected: false,
+            clients: 0,
+            messages_sent: 0,
+            client_queues: Vec::new(),
+            event_queue: Stats::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "mpv_connected": self.mpv_connected,
+            "clients": self.clients,
+            "messages_sent": self.messages_sent,
+            "client_queues": self
+                .client_queues
+                .iter()
+                .map(|queue| serde_json::json!({
+                    "id": queue.id,
+                    "queued": queue.queued,
+                    "dropped": queue.dropped,
+                }))
+                .collect::<Vec<_>>(),
+            "event_queue": {
+                "sent": self.event_queue.sent,
+                "dropped": self.event_queue.dropped,
+                "high_water_mark": self.event_queue.high_water_mark,
+            },
+            "uptime_seconds": self.started.elapsed().as_secs(),
+        })
+        .to_string()
+    }
+}
+
+/// Answers a request that is not a WebSocket upgrade. Files are served from
+/// `static_dir` if set, otherwise the bundled texthooker page is served at `/`
+pub(crate) fn respond(
+    request: &Request,
+    status: &ServerStatus,
+    static_dir: Option<&Path>,
+) -> Response {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Response::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "method not allowed\n",
+        );
+    }
+
+    let response = match (request.uri().path(), static_dir) {
+        ("/healthz", _) => Response::new(StatusCode::OK, "text/plain", "ok\n"),
+        ("/status", _) => Response::new(StatusCode::OK, "application/json", status.to_json()),
+        ("/version", _) => Response::new(
+            StatusCode::OK,
+            "text/plain",
+            concat!(env!("CARGO_PKG_VERSION"), "\n"),
+        ),
+        (path, Some(static_dir)) => static_file(static_dir, path),
+        ("/" | "/index.html", None) => {
+            Response::new(StatusCode::OK, "text/html; charset=utf-8", TEXTHOOKER_PAGE)
+        }
+        (_, None) => not_found(),
+    };
+
+    if request.method() == Method::HEAD {
+        response.without_body()
+    } else {
+        response
+    }
+}
+
+fn not_found() -> Response {
+    Response::new(StatusCode::NOT_FOUND, "text/plain", "not found\n")
+}
+
+fn static_file(static_dir: &Path, request_path: &str) -> Response {
+    let Some(mut path) = static_file_path(static_dir, request_path) else {
+        return not_found();
+    };
+    if path.is_dir() {
+        path.push("index.html");
+    }
+
+    match fs::read(&path) {
+        Ok(contents) => Response::new(StatusCode::OK, content_type(&path), contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => not_found(),
+        Err(e) => {
+            warn!("failed to read static file `{}`: {e}", path.display());
+            Response::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                "failed to read file\n",
+            )
+        }
+    }
+}
+
+/// The path of the requested file, or `None` if the request tries to escape
+/// `static_dir`
+fn static_file_path(static_dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let request_path = percent_decode(request_path)?;
+    let mut path = static_dir.to_owned();
+    for segment in request_path.split('/') {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        // Windows separators are rejected everywhere so that the same requests
+        // are served on every platform
+        if segment.contains('\\') {
+            return None;
+        }
+        // Rejects `..`, but also drive prefixes on Windows
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(component)), None) => path.push(component),
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Decodes the `%XX` escapes of a URI path or query value, rejecting malformed
+/// escapes and values that are not UTF-8
+pub(crate) fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A response written to a non-blocking stream over as many writable events as
+/// needed, after which the connection is closed
+pub(crate) struct Response {
+    bytes: Vec<u8>,
+    written: usize,
+}
+
+impl Response {
+    pub(crate) fn new(status: StatusCode, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        let body = body.into();
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: {content_type}\r\n\
+             Content-Length: {}\r\n\
+             Cache-Control: no-store\r\n\
+             Connection: close\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default(),
+            body.len(),
+        )
+        .into_bytes();
+        bytes.extend(body);
+        Self { bytes, written: 0 }
+    }
+
+    /// Keeps the headers, including the length of the body, but not the body
+    fn without_body(mut self) -> Self {
+        let head_length = self
+            .bytes
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(self.bytes.len(), |position| position + 4);
+        self.bytes.truncate(head_length);
+        self
+    }
+
+    /// Writes as much of the response as the stream accepts. Returns whether
+    /// the whole response was written
+    pub(crate) fn write_to(&mut self, stream: &mut impl Write) -> io::Result<bool> {
+        while self.written < self.bytes.len() {
+            match stream.write(&self.bytes[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match stream.flush() {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A stream that first returns the bytes already read from it, so that the
+/// WebSocket handshake can read the request that was peeked at
+pub(crate) struct RewindStream<S> {
+    buffer: Vec<u8>,
+    position: usize,
+    stream: S,
+}
+
+impl<S> RewindStream<S> {
+    pub(crate) fn new(buffer: Vec<u8>, stream: S) -> Self {
+        Self {
+            buffer,
+            position: 0,
+            stream,
+        }
+    }
+}
+
+impl<S: Read> Read for RewindStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            return self.stream.read(buf);
+        }
+
+        let n = (&self.buffer[self.position..]).read(buf)?;
+        self.position += n;
+        if self.position == self.buffer.len() {
+            self.buffer = Vec::new();
+            self.position = 0;
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for RewindStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Source> Source for RewindStream<S> {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.stream.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.stream.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.stream.deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("%e5%ad%97").as_deref(), Some("字"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+    }
+
+    #[test]
+    fn percent_decode_rejects_malformed_escapes() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("a%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%-1"), None);
+        // Not UTF-8
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn static_file_path_stays_in_the_static_directory() {
+        let static_dir = Path::new("/srv/texthooker");
+        assert_eq!(
+            static_file_path(static_dir, "/"),
+            Some(static_dir.to_owned())
+        );
+        assert_eq!(
+            static_file_path(static_dir, "/css/./style.css"),
+            Some(static_dir.join("css").join("style.css"))
+        );
+        assert_eq!(
+            static_file_path(static_dir, "/css%2Fstyle.css"),
+            Some(static_dir.join("css").join("style.css"))
+        );
+        assert_eq!(
+            static_file_path(static_dir, "//index.html"),
+            Some(static_dir.join("index.html"))
+        );
+    }
+
+    #[test]
+    fn static_file_path_rejects_traversal() {
+        let static_dir = Path::new("/srv/texthooker");
+        for request_path in [
+            "/..",
+            "/../etc/passwd",
+            "/css/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/%2E%2E%2Fetc%2Fpasswd",
+            "/css%2F..%2F..%2Fetc%2Fpasswd",
+            "/..\\etc\\passwd",
+            "/css\\..\\..\\etc",
+            "/%5c..%5cetc",
+        ] {
+            assert_eq!(
+                static_file_path(static_dir, request_path),
+                None,
+                "{request_path}"
+            );
+        }
+    }
+
+    #[test]
+    fn static_file_path_rejects_malformed_escapes() {
+        let static_dir = Path::new("/srv/texthooker");
+        assert_eq!(static_file_path(static_dir, "/index%2"), None);
+        assert_eq!(static_file_path(static_dir, "/%zzindex.html"), None);
+    }
+}
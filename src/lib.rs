@@ -0,0 +1,21 @@
+//! Streams mpv's subtitles to WebSocket clients.
+//!
+//! [`mpv::Client`] connects to mpv's JSON IPC socket and forwards the observed
+//! subtitles as [`event::Event`]s to a [`websocket::Server`], which broadcasts
+//! them to its clients. Both communicate through a [`mio_channel`], a bounded
+//! channel that can be polled alongside sockets.
+
+pub mod command;
+mod error;
+pub mod event;
+pub mod filter;
+mod framing;
+mod http;
+pub mod mio_channel;
+pub mod mpv;
+mod timer;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
+
+pub use error::Error;
@@ -1,90 +1,433 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use config::{Config, LogFormat, LogLevel, LogRotation, Logging};
+use mpv_websocket::event::OutputFormat;
+#[cfg(feature = "tls")]
+use mpv_websocket::tls;
+use mpv_websocket::{mpv, websocket};
 use std::backtrace::Backtrace;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::io::{self, IsTerminal};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::panic::{self, PanicHookInfo};
+#[cfg(feature = "tls")]
+use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Mutex;
-use tracing::error;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, Subscriber};
 use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::EitherWriter;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 
-mod mio_channel;
-mod mpv;
-mod websocket;
+mod config;
 
+// Arguments, which can also be set with environment variables for logging,
+// override the values of the configuration file, whose defaults are in
+// `Config::default`
 #[derive(Parser)]
 struct Args {
     #[arg(short, long)]
-    mpvsocket_path: PathBuf,
+    mpvsocket_path: Option<PathBuf>,
 
-    #[arg(short('a'), long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
-    websocket_server_bind_address: IpAddr,
+    #[arg(short('a'), long)]
+    websocket_server_bind_address: Option<IpAddr>,
 
-    #[arg(short('p'), visible_short_alias('w'), long, default_value_t = 6677)]
-    websocket_server_port: u16,
+    #[arg(short('p'), visible_short_alias('w'), long)]
+    websocket_server_port: Option<u16>,
 
     #[arg(short('s'), long, default_value_t = false)]
     secondary_subtitles: bool,
+
+    #[arg(short('f'), long, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// Number of recent subtitles kept for clients connecting with the
+    /// `history` query parameter
+    #[arg(long)]
+    history_size: Option<usize>,
+
+    /// Shared secret that clients must send to connect, as the `token` query
+    /// parameter, a `Sec-WebSocket-Protocol` value or an `Authorization:
+    /// Bearer` header
+    #[arg(long, env = "MPV_WEBSOCKET_AUTH_TOKEN", hide_env_values = true)]
+    auth_token: Option<String>,
+
+    /// Origin of a page allowed to connect, in which `*` matches any
+    /// characters, for example `https://*.example.com`. Can be repeated. Every
+    /// origin is allowed by default
+    #[arg(long = "allowed-origin")]
+    allowed_origins: Vec<String>,
+
+    /// Serve the files of this directory over HTTP instead of the bundled
+    /// texthooker page
+    #[arg(long)]
+    static_dir: Option<PathBuf>,
+
+    /// Seconds a WebSocket client can stay silent before it is pinged, or 0
+    /// to never ping clients
+    #[arg(long)]
+    ping_interval: Option<u64>,
+
+    /// Seconds a WebSocket client has to answer a ping before it is
+    /// disconnected
+    #[arg(long)]
+    pong_timeout: Option<u64>,
+
+    /// Seconds a connection has to finish its TLS handshake and send its
+    /// request before it is closed
+    #[arg(long)]
+    request_timeout: Option<u64>,
+
+    /// Number of messages queued for a WebSocket client that does not read
+    /// them as fast as they are sent, beyond which `--slow-client-policy`
+    /// applies
+    #[arg(long)]
+    max_queued_messages: Option<NonZeroUsize>,
+
+    /// What happens to the messages of a client whose queue is full
+    #[arg(long, value_enum)]
+    slow_client_policy: Option<websocket::SlowClientPolicy>,
+
+    /// What happens to mpv responses that are not valid UTF-8, which happens
+    /// with subtitle files in another encoding
+    #[arg(long, value_enum)]
+    invalid_utf8: Option<mpv::InvalidUtf8>,
+
+    /// Decode mpv responses that are not valid UTF-8 as EUC-JP or Shift_JIS
+    /// if possible, before `--invalid-utf8` applies
+    #[arg(long, default_value_t = false)]
+    charset_fallback: bool,
+
+    /// mpv property whose changes are broadcast to the clients, for example
+    /// `media-title` or `pause`. Can be repeated
+    #[arg(long = "observe")]
+    observe: Vec<String>,
+
+    /// Path to a TOML configuration file. Defaults to `mpv_websocket.toml`
+    /// next to the executable, if it exists
+    #[arg(short('c'), long)]
+    config: Option<PathBuf>,
+
+    /// Minimum level of the logged messages
+    #[arg(long, value_enum, env = "MPV_WEBSOCKET_LOG_LEVEL")]
+    log_level: Option<LogLevel>,
+
+    /// Directory of the log files. Defaults to `logs` next to the executable
+    #[arg(long, env = "MPV_WEBSOCKET_LOG_DIR")]
+    log_dir: Option<PathBuf>,
+
+    /// How often a new log file is started
+    #[arg(long, value_enum, env = "MPV_WEBSOCKET_LOG_ROTATION")]
+    log_rotation: Option<LogRotation>,
+
+    /// Number of log files kept, the oldest ones are deleted. All of them are
+    /// kept by default
+    #[arg(long, env = "MPV_WEBSOCKET_LOG_MAX_FILES")]
+    log_max_files: Option<NonZeroUsize>,
+
+    /// Format of the logged messages
+    #[arg(long, value_enum, env = "MPV_WEBSOCKET_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// PEM encoded certificate chain to serve WebSocket connections over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_certificate: Option<PathBuf>,
+
+    /// PEM encoded private key of the TLS certificate
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// Serve WebSocket connections over TLS with a self-signed certificate,
+    /// generated on the first run if neither the certificate nor the key
+    /// exists
+    #[cfg(feature = "tls")]
+    #[arg(long, default_value_t = false)]
+    tls_self_signed: bool,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long, default_value_t = false)]
+    print_config: bool,
+}
+
+impl Args {
+    fn override_config(self, config: &mut Config) {
+        if let Some(mpvsocket_path) = self.mpvsocket_path {
+            config.mpvsocket_path = Some(mpvsocket_path);
+        }
+        if let Some(address) = self.websocket_server_bind_address {
+            config.websocket_server_bind_address = address;
+        }
+        if let Some(port) = self.websocket_server_port {
+            config.websocket_server_port = port;
+        }
+        if self.secondary_subtitles {
+            config.subtitles = websocket::Subscription::Secondary;
+        }
+        if let Some(output_format) = self.output_format {
+            config.output_format = output_format;
+        }
+        if let Some(history_size) = self.history_size {
+            config.history_size = history_size;
+        }
+        if let Some(auth_token) = self.auth_token {
+            config.auth_token = Some(auth_token);
+        }
+        if !self.allowed_origins.is_empty() {
+            config.allowed_origins = self.allowed_origins;
+        }
+        if let Some(static_dir) = self.static_dir {
+            config.static_dir = Some(static_dir);
+        }
+        if let Some(ping_interval) = self.ping_interval {
+            config.ping_interval = ping_interval;
+        }
+        if let Some(pong_timeout) = self.pong_timeout {
+            config.pong_timeout = pong_timeout;
+        }
+        if let Some(request_timeout) = self.request_timeout {
+            config.request_timeout = request_timeout;
+        }
+        if let Some(max_queued_messages) = self.max_queued_messages {
+            config.max_queued_messages = max_queued_messages;
+        }
+        if let Some(slow_client_policy) = self.slow_client_policy {
+            config.slow_client_policy = slow_client_policy;
+        }
+        if let Some(invalid_utf8) = self.invalid_utf8 {
+            config.invalid_utf8 = invalid_utf8;
+        }
+        if self.charset_fallback {
+            config.charset_fallback = true;
+        }
+        if !self.observe.is_empty() {
+            config.observe = self.observe;
+        }
+        if let Some(level) = self.log_level {
+            config.logging.level = level;
+        }
+        if let Some(directory) = self.log_dir {
+            config.logging.directory = Some(directory);
+        }
+        if let Some(rotation) = self.log_rotation {
+            config.logging.rotation = rotation;
+        }
+        if let Some(max_files) = self.log_max_files {
+            config.logging.max_files = Some(max_files);
+        }
+        if let Some(format) = self.log_format {
+            config.logging.format = format;
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(certificate) = self.tls_certificate {
+                config.tls.certificate = Some(certificate);
+            }
+            if let Some(key) = self.tls_key {
+                config.tls.key = Some(key);
+            }
+            if self.tls_self_signed {
+                config.tls.self_signed = true;
+            }
+        }
+    }
 }
 
+#[derive(Clone)]
 struct LazyFileLogger {
     log_dir: PathBuf,
-    state: Mutex<Option<(NonBlocking, WorkerGuard)>>,
+    rotation: Rotation,
+    max_files: Option<NonZeroUsize>,
+    suffix: &'static str,
+    state: Arc<Mutex<LogFile>>,
+}
+
+enum LogFile {
+    Unopened,
+    Open {
+        writer: NonBlocking,
+        // Writes the buffered logs to the file when dropped
+        _guard: WorkerGuard,
+    },
+    Unavailable,
 }
 
 impl LazyFileLogger {
-    fn new(log_dir: PathBuf) -> Self {
+    fn new(logging: &Logging, default_log_dir: PathBuf) -> Self {
         Self {
-            log_dir,
-            state: Mutex::new(None),
+            log_dir: logging.directory.clone().unwrap_or(default_log_dir),
+            rotation: logging.rotation.into(),
+            max_files: logging.max_files,
+            suffix: match logging.format {
+                LogFormat::Text => "txt",
+                LogFormat::Json => "json",
+            },
+            state: Arc::new(Mutex::new(LogFile::Unopened)),
         }
     }
+
+    /// Writes the buffered logs to the log file and stops logging to it, so
+    /// that nothing is lost when the process exits
+    fn close(&self) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        *state = LogFile::Unavailable;
+    }
 }
 
 impl<'a> MakeWriter<'a> for LazyFileLogger {
-    type Writer = NonBlocking;
+    type Writer = EitherWriter<NonBlocking, io::Sink>;
 
     fn make_writer(&'a self) -> Self::Writer {
-        let mut guard = self.state.lock().unwrap();
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
 
-        if let Some((writer, _)) = guard.as_ref() {
-            return writer.clone();
+        match &*state {
+            LogFile::Open { writer, .. } => return EitherWriter::A(writer.clone()),
+            LogFile::Unavailable => return EitherWriter::B(io::sink()),
+            LogFile::Unopened => {}
         }
 
-        let file_appender = RollingFileAppender::builder()
-            .rotation(Rotation::DAILY)
+        let mut builder = RollingFileAppender::builder()
+            .rotation(self.rotation.clone())
             .filename_prefix("mpv_websocket")
-            .filename_suffix(".txt")
-            .build(&self.log_dir)
-            .expect("Failed to create rolling file appender");
+            .filename_suffix(self.suffix);
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files.get());
+        }
+        let file_appender = match builder.build(&self.log_dir) {
+            Ok(file_appender) => file_appender,
+            Err(e) => {
+                // Logging the error would call this function again
+                eprintln!(
+                    "failed to create log file in `{}`: {e}. Logging to stderr only.",
+                    self.log_dir.display()
+                );
+                *state = LogFile::Unavailable;
+                return EitherWriter::B(io::sink());
+            }
+        };
 
         let (non_blocking_writer, worker_guard) = tracing_appender::non_blocking(file_appender);
-        *guard = Some((non_blocking_writer.clone(), worker_guard));
-        non_blocking_writer
+        *state = LogFile::Open {
+            writer: non_blocking_writer.clone(),
+            _guard: worker_guard,
+        };
+        EitherWriter::A(non_blocking_writer)
+    }
+}
+
+fn log_layer<S, W>(writer: W, format: LogFormat, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(ansi)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(writer)
+            .boxed(),
     }
 }
 
-fn main() {
-    let log_dir = if let Ok(mut exe_path) = std::env::current_exe() {
+/// Loads the TLS certificate, generating a self-signed one first if enabled
+#[cfg(feature = "tls")]
+fn configure_tls(
+    server: websocket::Server,
+    config: &Config,
+    executable_dir: &Path,
+) -> Result<websocket::Server, mpv_websocket::Error> {
+    if !config.tls.is_enabled() {
+        return Ok(server);
+    }
+
+    let tls_dir = executable_dir.join("tls");
+    let certificate = config
+        .tls
+        .certificate
+        .clone()
+        .unwrap_or_else(|| tls_dir.join("certificate.pem"));
+    let key = config
+        .tls
+        .key
+        .clone()
+        .unwrap_or_else(|| tls_dir.join("key.pem"));
+    if config.tls.self_signed {
+        let mut subject_alt_names = vec![
+            "localhost".to_owned(),
+            "127.0.0.1".to_owned(),
+            "::1".to_owned(),
+        ];
+        if !config.websocket_server_bind_address.is_unspecified() {
+            subject_alt_names.push(config.websocket_server_bind_address.to_string());
+        }
+        tls::generate_self_signed_certificate(&certificate, &key, subject_alt_names)?;
+    }
+
+    Ok(server.tls(tls::load_server_config(&certificate, &key)?))
+}
+
+fn main() -> ExitCode {
+    let executable_dir = if let Ok(mut exe_path) = std::env::current_exe() {
         exe_path.pop();
-        exe_path.join("logs")
+        exe_path
     } else {
-        PathBuf::from("logs")
+        PathBuf::new()
+    };
+
+    let args = Args::parse();
+    let mut config = match Config::load(args.config.as_deref(), &executable_dir) {
+        Ok(config) => config,
+        Err(e) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+    };
+    let print_config = args.print_config;
+    args.override_config(&mut config);
+    if let Err(e) = config.validate() {
+        Args::command().error(ErrorKind::InvalidValue, e).exit();
+    }
+
+    if print_config {
+        print!("{}", config.to_toml());
+        return ExitCode::SUCCESS;
+    }
+
+    let Some(mpvsocket_path) = config.mpvsocket_path.clone() else {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the mpv socket path must be set with `--mpvsocket-path` or \
+                 `mpvsocket_path` in the config file",
+            )
+            .exit();
     };
 
-    let file_logger = LazyFileLogger::new(log_dir);
+    let file_logger = LazyFileLogger::new(&config.logging, executable_dir.join("logs"));
     tracing_subscriber::registry()
-        .with(LevelFilter::WARN)
-        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_writer(file_logger) // <-- Use our lazy logger here
-                .with_ansi(false),
-        )
+        .with(LevelFilter::from(config.logging.level))
+        .with(log_layer(
+            std::io::stderr,
+            config.logging.format,
+            // The Lua script shows errors to the user, without colors
+            std::io::stderr().is_terminal(),
+        ))
+        .with(log_layer(file_logger.clone(), config.logging.format, false))
         .init();
 
     panic::set_hook(Box::new(|panic_info: &PanicHookInfo| {
@@ -105,22 +448,56 @@ fn main() {
         );
     }));
 
-    let args = Args::parse();
-
     println!(
         "Starting WebSocket server at `{}:{}`",
-        args.websocket_server_bind_address, args.websocket_server_port
+        config.websocket_server_bind_address, config.websocket_server_port
     );
-    let server = websocket::Server::new(SocketAddr::new(
-        args.websocket_server_bind_address,
-        args.websocket_server_port,
+    let mut server = websocket::Server::new(SocketAddr::new(
+        config.websocket_server_bind_address,
+        config.websocket_server_port,
     ))
-    .start();
+    .output_format(config.output_format)
+    .default_subscription(config.subtitles)
+    .history_size(config.history_size)
+    .keepalive(
+        Duration::from_secs(config.ping_interval),
+        Duration::from_secs(config.pong_timeout),
+    )
+    .request_timeout(Duration::from_secs(config.request_timeout))
+    .max_queued_messages(config.max_queued_messages)
+    .slow_client_policy(config.slow_client_policy)
+    .allowed_origins(config.allowed_origins.clone());
+    if let Some(auth_token) = config.auth_token.clone() {
+        server = server.auth_token(auth_token);
+    }
+    if let Some(static_dir) = config.static_dir.clone() {
+        server = server.static_dir(static_dir);
+    }
+    #[cfg(feature = "tls")]
+    let server = configure_tls(server, &config, &executable_dir);
+    #[cfg(not(feature = "tls"))]
+    let server = Ok(server);
+    let result = server
+        .and_then(websocket::Server::start)
+        .and_then(|server| {
+            println!("Connecting to mpv socket at `{}`", mpvsocket_path.display());
+            mpv::Client::new(mpvsocket_path)
+                .filter(config.filters)
+                .invalid_utf8(config.invalid_utf8)
+                .charset_fallback(config.charset_fallback)
+                .observe(config.observe)
+                // The timings are only sent in the JSON output format
+                .playback_position(config.output_format == OutputFormat::Json)
+                .poll_and_send_messages_to_server(server)
+        });
 
-    println!(
-        "Connecting to mpv socket at `{}`",
-        args.mpvsocket_path.display()
-    );
-    mpv::Client::new(args.mpvsocket_path, args.secondary_subtitles)
-        .poll_and_send_messages_to_server(server);
+    let exit_code = match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{e}");
+            ExitCode::from(e.exit_code())
+        }
+    };
+    file_logger.close();
+    exit_code
 }
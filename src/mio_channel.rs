@@ -1,54 +1,244 @@
-use std::{
-    io,
-    sync::{mpsc, Arc, Mutex},
-};
+//! A bounded channel whose [`Receiver`] can be registered with a [`mio::Poll`],
+//! so that a thread can wait on sockets and messages at the same time.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{SendError, TryRecvError, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 
 use mio::{event::Source, Token, Waker};
+use tracing::warn;
+
+/// What happens to a message sent while the channel is full
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    /// [`SyncSender::send`] waits until the receiver makes room and
+    /// [`SyncSender::try_send`] fails
+    Block,
+    /// The message is dropped
+    DropNewest,
+    /// The oldest message in the channel is dropped to make room
+    DropOldest,
+}
 
+/// Counters of a channel, shared by its senders and its receiver
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stats {
+    /// The number of messages accepted by the channel, including the ones
+    /// later dropped by [`Overflow::DropOldest`]
+    pub sent: u64,
+    /// The number of messages dropped because the channel was full
+    pub dropped: u64,
+    /// The largest number of messages the channel held at once
+    pub high_water_mark: usize,
+}
+
+/// Creates a channel holding at most `bound` messages, whose senders block
+/// while it is full. Once registered, the receiver becomes readable when a
+/// message is sent or the last sender is dropped
 pub fn sync_channel<T>(bound: u32) -> (SyncSender<T>, Receiver<T>) {
-    let (tx, rx) = mpsc::sync_channel(bound as usize);
+    sync_channel_with_overflow(bound, Overflow::Block)
+}
 
-    let waker = Arc::new(Mutex::new(None));
+/// Creates a channel holding at most `bound` messages, at least one, that
+/// applies `overflow` to the messages sent while it is full
+pub fn sync_channel_with_overflow<T>(
+    bound: u32,
+    overflow: Overflow,
+) -> (SyncSender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            senders: 1,
+            receiver_connected: true,
+            stats: Stats::default(),
+        }),
+        not_full: Condvar::new(),
+        bound: (bound as usize).max(1),
+        overflow,
+        waker: Mutex::new(None),
+    });
 
     (
         SyncSender {
-            waker: waker.clone(),
-            tx,
+            shared: shared.clone(),
         },
-        Receiver { waker, rx },
+        Receiver { shared },
     )
 }
 
-#[derive(Clone)]
+struct State<T> {
+    messages: VecDeque<T>,
+    senders: usize,
+    receiver_connected: bool,
+    stats: Stats,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Notified when a message is received or the receiver is dropped
+    not_full: Condvar,
+    bound: usize,
+    overflow: Overflow,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds `t` to the channel, applying the overflow policy if it is full
+    fn push(&self, mut state: MutexGuard<'_, State<T>>, t: T) -> Result<(), TrySendError<T>> {
+        if !state.receiver_connected {
+            return Err(TrySendError::Disconnected(t));
+        }
+
+        if state.messages.len() >= self.bound {
+            match self.overflow {
+                Overflow::Block => return Err(TrySendError::Full(t)),
+                Overflow::DropNewest => {
+                    state.stats.dropped += 1;
+                    return Ok(());
+                }
+                Overflow::DropOldest => {
+                    state.messages.pop_front();
+                    state.stats.dropped += 1;
+                }
+            }
+        }
+
+        state.messages.push_back(t);
+        state.stats.sent += 1;
+        state.stats.high_water_mark = state.stats.high_water_mark.max(state.messages.len());
+        drop(state);
+        self.wake();
+        Ok(())
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = &*self.waker.lock().unwrap_or_else(PoisonError::into_inner) {
+            // The receiver still gets the message on its next wakeup
+            if let Err(e) = waker.wake() {
+                warn!("failed to wake the receiver of a channel: {e}");
+            }
+        }
+    }
+}
+
+/// The sending half of a [`sync_channel`], which can be cloned and sent to
+/// other threads
 pub struct SyncSender<T> {
-    waker: Arc<Mutex<Option<Waker>>>,
-    tx: mpsc::SyncSender<T>,
+    shared: Arc<Shared<T>>,
 }
 
 impl<T> SyncSender<T> {
-    pub fn send(&self, t: T) -> Result<(), mpsc::SendError<T>> {
-        self.tx.send(t)?;
+    /// Sends `t`, blocking while the channel is full unless it drops messages.
+    /// Fails if the receiver was dropped
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.lock();
+        if self.shared.overflow == Overflow::Block {
+            while state.receiver_connected && state.messages.len() >= self.shared.bound {
+                state = self
+                    .shared
+                    .not_full
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
 
-        if let Some(waker) = &*self
-            .waker
-            .lock()
-            .unwrap_or_else(std::sync::PoisonError::into_inner)
-        {
-            waker.wake().expect("unable to wake");
+        self.shared.push(state, t).map_err(|e| match e {
+            TrySendError::Full(t) | TrySendError::Disconnected(t) => SendError(t),
+        })
+    }
+
+    /// Sends `t` without blocking, failing if the channel is full and blocks
+    /// its senders or if the receiver was dropped
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.shared.push(self.shared.lock(), t)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.shared.lock().stats
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
         }
+    }
+}
 
-        Ok(())
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        let disconnected = state.senders == 0;
+        drop(state);
+        // The receiver is woken up so that it notices when the last sender is
+        // dropped
+        if disconnected {
+            self.shared.wake();
+        }
     }
 }
 
+/// The receiving half of a [`sync_channel`]. Wakeups are coalesced, so a
+/// single readiness event can stand for several messages: every event has to
+/// be followed by receiving until the channel is empty, for example with
+/// [`Receiver::try_iter`], or the remaining messages wait for the next send
 pub struct Receiver<T> {
-    waker: Arc<Mutex<Option<Waker>>>,
-    rx: mpsc::Receiver<T>,
+    shared: Arc<Shared<T>>,
 }
 
 impl<T> Receiver<T> {
-    pub fn try_recv(&self) -> Result<T, mpsc::TryRecvError> {
-        self.rx.try_recv()
+    /// Receives a message without blocking
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.lock();
+        match state.messages.pop_front() {
+            Some(t) => {
+                drop(state);
+                self.shared.not_full.notify_one();
+                Ok(t)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Receives the pending messages without blocking, until the channel is
+    /// empty or disconnected
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { receiver: self }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.shared.lock().stats
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_connected = false;
+        // Blocked senders fail instead of waiting forever
+        self.shared.not_full.notify_all();
+    }
+}
+
+/// An iterator over the pending messages of a [`Receiver`], see
+/// [`Receiver::try_iter`]
+pub struct TryIter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.try_recv().ok()
     }
 }
 
@@ -60,9 +250,10 @@ impl<T> Source for Receiver<T> {
         _: mio::Interest,
     ) -> io::Result<()> {
         let mut waker = self
+            .shared
             .waker
             .lock()
-            .unwrap_or_else(std::sync::PoisonError::into_inner);
+            .unwrap_or_else(PoisonError::into_inner);
         if waker.is_none() {
             *waker = Some(Waker::new(registry, token)?);
         }
@@ -82,10 +273,149 @@ impl<T> Source for Receiver<T> {
 
     fn deregister(&mut self, _: &mio::Registry) -> io::Result<()> {
         let mut waker = self
+            .shared
             .waker
             .lock()
-            .unwrap_or_else(std::sync::PoisonError::into_inner);
+            .unwrap_or_else(PoisonError::into_inner);
         *waker = None;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use mio::{Events, Interest, Poll};
+
+    use super::*;
+
+    const RECEIVER: Token = Token(0);
+
+    /// Receives everything sent on the channel until it is disconnected,
+    /// draining it on every wakeup like the poll loops do
+    fn receive_all<T>(poll: &mut Poll, receiver: &Receiver<T>) -> Vec<T> {
+        let mut events = Events::with_capacity(8);
+        let mut received = Vec::new();
+        loop {
+            poll.poll(&mut events, Some(Duration::from_secs(5)))
+                .expect("poll should not fail");
+            assert!(!events.is_empty(), "timed out waiting for messages");
+
+            received.extend(receiver.try_iter());
+            if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
+                return received;
+            }
+        }
+    }
+
+    #[test]
+    fn burst_arrives_complete_and_in_order() {
+        let (sender, mut receiver) = sync_channel(10);
+        let mut poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut receiver, RECEIVER, Interest::READABLE)
+            .unwrap();
+
+        let burst = thread::spawn(move || {
+            for line in 0..1000 {
+                sender.send(line).unwrap();
+            }
+        });
+        let received = receive_all(&mut poll, &receiver);
+        burst.join().unwrap();
+
+        assert_eq!(received, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn one_wakeup_drains_every_pending_message() {
+        let (sender, mut receiver) = sync_channel(10);
+        let mut poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut receiver, RECEIVER, Interest::READABLE)
+            .unwrap();
+
+        for line in 0..10 {
+            sender.send(line).unwrap();
+        }
+        let mut events = Events::with_capacity(8);
+        poll.poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(!events.is_empty());
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn messages_sent_before_disconnecting_are_received() {
+        let (sender, mut receiver) = sync_channel(10);
+        let mut poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut receiver, RECEIVER, Interest::READABLE)
+            .unwrap();
+
+        for line in 0..5 {
+            sender.send(line).unwrap();
+        }
+        drop(sender);
+
+        assert_eq!(receive_all(&mut poll, &receiver), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_messages() {
+        let (sender, receiver) = sync_channel_with_overflow(3, Overflow::DropOldest);
+        for line in 0..10 {
+            sender.send(line).unwrap();
+        }
+
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![7, 8, 9]);
+        let stats = receiver.stats();
+        assert_eq!(
+            (stats.sent, stats.dropped, stats.high_water_mark),
+            (10, 7, 3)
+        );
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_first_messages() {
+        let (sender, receiver) = sync_channel_with_overflow(3, Overflow::DropNewest);
+        for line in 0..10 {
+            sender.try_send(line).unwrap();
+        }
+
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        let stats = sender.stats();
+        assert_eq!(
+            (stats.sent, stats.dropped, stats.high_water_mark),
+            (3, 7, 3)
+        );
+    }
+
+    #[test]
+    fn blocking_try_send_fails_when_full() {
+        let (sender, receiver) = sync_channel(1);
+        sender.try_send(0).unwrap();
+
+        assert!(matches!(sender.try_send(1), Err(TrySendError::Full(1))));
+        assert_eq!(receiver.try_recv(), Ok(0));
+        sender.try_send(2).unwrap();
+    }
+
+    #[test]
+    fn blocked_sender_fails_once_the_receiver_is_dropped() {
+        let (sender, receiver) = sync_channel(1);
+        sender.send(0).unwrap();
+
+        let blocked = thread::spawn(move || sender.send(1));
+        thread::sleep(Duration::from_millis(50));
+        drop(receiver);
+
+        assert!(matches!(blocked.join().unwrap(), Err(SendError(1))));
+    }
+}
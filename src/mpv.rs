@@ -1,25 +1,35 @@
+use std::borrow::Cow;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use clap::ValueEnum;
+use encoding_rs::{EUC_JP, SHIFT_JIS};
 use mio::event::Source;
 #[cfg(unix)]
 use mio::net::UnixStream;
 #[cfg(windows)]
 use mio::windows::NamedPipe;
 use mio::{Events, Interest, Poll, Token};
-use serde::Deserialize;
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt::Display;
 use std::io::{self, Read, Write};
 #[cfg(windows)]
 use std::path::Path;
+use std::sync::mpsc::TryRecvError;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
+use crate::command::Command;
+use crate::event::{Event, LifecycleEvent, MediaMetadata, Property};
+use crate::filter::Filter;
+use crate::framing::LineDecoder;
 use crate::mio_channel::SyncSender;
-use crate::{mio_channel, websocket};
+use crate::websocket;
 
 const CLIENT: Token = Token(0);
-const BROADCAST: Token = Token(CLIENT.0 + 1);
+const COMMANDS: Token = Token(CLIENT.0 + 1);
 
 #[cfg(windows)]
 fn create_named_pipe<P: AsRef<Path>>(path: P) -> Result<NamedPipe, io::Error> {
@@ -81,15 +91,19 @@ impl Source for EmptyStream {
     }
 }
 
+/// An error on the connection to mpv
 #[derive(Debug)]
-enum SocketError {
+pub enum SocketError {
     Io(io::Error),
+    /// The WebSocket server stopped receiving events
+    ServerStopped,
 }
 
 impl Display for SocketError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SocketError::Io(error) => write!(f, "IO error: {error}"),
+            SocketError::ServerStopped => write!(f, "the WebSocket server stopped"),
         }
     }
 }
@@ -98,6 +112,7 @@ impl Error for SocketError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             SocketError::Io(error) => Some(error),
+            SocketError::ServerStopped => None,
         }
     }
 }
@@ -112,11 +127,19 @@ enum SocketMessage {
     MessagesAvailable,
     CanWrite,
     SendText(Arc<str>),
+    SendCommand(Command),
+    /// Asks mpv for the metadata of the file it is playing
+    RequestMetadata,
 }
 
 enum SocketState {
-    Connected(ConnectedState),
-    Closed(Box<dyn Stream>),
+    Connected(Box<ConnectedState>),
+    Closed {
+        stream: Box<dyn Stream>,
+        /// Whether mpv announced that it is quitting before closing the
+        /// connection
+        shutdown: bool,
+    },
 }
 
 impl SocketState {
@@ -127,11 +150,23 @@ impl SocketState {
                     *self = state;
                 }
             }
-            SocketState::Closed(_) => panic!("socket is already closed"),
+            SocketState::Closed { .. } => panic!("socket is already closed"),
         }
 
         Ok(())
     }
+
+    fn close(&mut self) {
+        if let SocketState::Connected(state) = self {
+            // If the server stopped, the next event sent to it reports it
+            let _ = state.finish_pending();
+            let stream = std::mem::replace(&mut state.stream, Box::new(EmptyStream));
+            *self = SocketState::Closed {
+                stream,
+                shutdown: false,
+            };
+        }
+    }
 }
 
 enum WriteState {
@@ -141,76 +176,118 @@ enum WriteState {
 
 struct ConnectedState {
     stream: Box<dyn Stream>,
+    lines: LineDecoder,
     messages: VecDeque<Arc<str>>,
     write: WriteState,
-    sender: SyncSender<Arc<str>>,
+    sender: SyncSender<Event>,
+    filter: Filter,
+    decoding: Decoding,
+    observed_properties: Arc<[String]>,
+    /// Whether subtitles wait for the playback position before being sent
+    playback_position: bool,
+    timing: SubtitleTiming,
+    pending_subtitles: VecDeque<PendingSubtitle>,
+    pending_commands: HashMap<u64, Command>,
+    /// The metadata properties mpv has not replied with yet, by request id
+    pending_metadata: HashMap<u64, MetadataProperty>,
+    metadata: MediaMetadata,
+    next_request_id: u64,
 }
 
 impl ConnectedState {
+    fn new(
+        stream: Box<dyn Stream>,
+        sender: SyncSender<Event>,
+        filter: Filter,
+        decoding: Decoding,
+        observed_properties: Arc<[String]>,
+        playback_position: bool,
+    ) -> Self {
+        Self {
+            stream,
+            lines: LineDecoder::new(MAX_RESPONSE_LENGTH),
+            messages: VecDeque::new(),
+            write: WriteState::Unwritable,
+            sender,
+            filter,
+            decoding,
+            observed_properties,
+            playback_position,
+            timing: SubtitleTiming::default(),
+            pending_subtitles: VecDeque::new(),
+            pending_commands: HashMap::new(),
+            pending_metadata: HashMap::new(),
+            metadata: MediaMetadata::default(),
+            // mpv uses 0 for commands sent without a request id
+            next_request_id: 1,
+        }
+    }
+
+    fn transition_to_closed(&mut self) -> Result<Option<SocketState>, SocketError> {
+        self.close(false)
+    }
+
+    fn close(&mut self, shutdown: bool) -> Result<Option<SocketState>, SocketError> {
+        self.finish_pending()?;
+        let stream = std::mem::replace(&mut self.stream, Box::new(EmptyStream));
+        Ok(Some(SocketState::Closed { stream, shutdown }))
+    }
+
+    /// Rejects the commands mpv did not reply to and sends the subtitles that
+    /// wait for the playback position without it, before the connection is
+    /// closed
+    fn finish_pending(&mut self) -> Result<(), SocketError> {
+        for (_, command) in self.pending_commands.drain() {
+            self.sender
+                .send(command.reject("mpv disconnected before replying"))
+                .map_err(|_| SocketError::ServerStopped)?;
+        }
+        while let Some(subtitle) = self.pending_subtitles.pop_front() {
+            self.send_subtitle(subtitle, None)?;
+        }
+        Ok(())
+    }
+
+    fn next_request_id(&mut self) -> u64 {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        request_id
+    }
+
     fn next_state(&mut self, message: SocketMessage) -> Result<Option<SocketState>, SocketError> {
         match message {
             SocketMessage::MessagesAvailable => {
-                let mut buffer = Vec::new();
-                let mut internal_buffer = [0; 8192];
+                let mut buffer = [0; 8192];
 
+                // The socket is read until it would block, as mio requires.
+                // mpv ends each response with a newline, and a read may end in
+                // the middle of a response, whose start is kept by the decoder
+                // until the rest of it is read
                 loop {
-                    match self.stream.read(&mut internal_buffer) {
-                        Ok(0) => {
-                            let (sender, _) = mio_channel::sync_channel::<Arc<str>>(1);
-                            let state = std::mem::replace(
-                                self,
-                                ConnectedState {
-                                    stream: Box::new(EmptyStream),
-                                    messages: VecDeque::new(),
-                                    write: WriteState::Unwritable,
-                                    sender,
-                                },
-                            );
-                            return Ok(Some(SocketState::Closed(state.stream)));
-                        }
-                        Ok(n) => {
-                            buffer.extend_from_slice(&internal_buffer[..n]);
-                        }
+                    let n = match self.stream.read(&mut buffer) {
+                        Ok(0) => return self.transition_to_closed(),
+                        Ok(n) => n,
                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
                         Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
                         Err(e) => return Err(From::from(e)),
-                    }
-
-                    let last_byte = match buffer.last() {
-                        Some(last_byte) => *last_byte,
-                        None => UTF8_NULL_CHARACTER,
                     };
-                    // mpv ends each response with a newline.
-                    //
-                    // The buffer may not be completely filled with the full
-                    // response, so we should continue reading
-                    if last_byte == UTF8_NEWLINE_CHARACTER {
-                        break;
-                    }
-                }
 
-                let responses =
-                    std::str::from_utf8(&buffer).expect("mpv should respond with UTF-8 strings");
-
-                // There may be multiple responses in the buffer, separated by a
-                // newline
-                for line in responses.lines() {
-                    let event = match serde_json::from_str::<PropertyChangeEvent>(line) {
-                        Ok(event) => event,
-                        Err(_) => {
-                            // mpv sends other event changes in the socket that
-                            // we don't care about
+                    for line in self.lines.decode(&buffer[..n]) {
+                        let line = match line {
+                            Ok(line) => line,
+                            Err(e) => {
+                                warn!("skipping mpv response: {e}");
+                                continue;
+                            }
+                        };
+                        let Some(line) = self.decoding.decode(&line) else {
                             continue;
+                        };
+                        if let Some(state) = self.handle_line(&line)? {
+                            return Ok(Some(state));
                         }
-                    };
-
-                    let data: Arc<str> = event.data.into();
-                    self.sender.send(data.clone()).unwrap_or_else(|e| {
-                        panic!("failed to send text `{data}` to WebSocket clients: {e:?}")
-                    });
+                    }
                 }
-
-                Ok(None)
             }
             SocketMessage::CanWrite => {
                 if let WriteState::Unwritable = self.write {
@@ -224,35 +301,245 @@ impl ConnectedState {
                 // respond to each message since it is done one at a time
                 self.send_message()
             }
-            SocketMessage::SendText(message) => {
-                self.messages.push_back(message);
+            SocketMessage::SendText(message) => self.queue_message(message),
+            SocketMessage::SendCommand(command) => {
+                let request_id = self.next_request_id();
+                let message = serde_json::json!({
+                    "command": command.command,
+                    "request_id": request_id,
+                })
+                .to_string()
+                    + "\n";
+                self.pending_commands.insert(request_id, command);
+                self.queue_message(message.into())
+            }
+            SocketMessage::RequestMetadata => self.request_metadata(),
+        }
+    }
 
-                if let WriteState::Unwritable = self.write {
-                    return Ok(None);
-                }
+    fn request_metadata(&mut self) -> Result<Option<SocketState>, SocketError> {
+        // Replies to an earlier request are ignored, they may belong to the
+        // previous file
+        self.pending_metadata.clear();
+        self.metadata = MediaMetadata::default();
 
-                self.send_message()
+        for property in MetadataProperty::ALL {
+            let request_id = self.next_request_id();
+            self.pending_metadata.insert(request_id, property);
+            let message = serde_json::json!({
+                "command": ["get_property", property.name()],
+                "request_id": request_id,
+            })
+            .to_string()
+                + "\n";
+            if let Some(state) = self.queue_message(message.into())? {
+                return Ok(Some(state));
             }
         }
+        Ok(None)
+    }
+
+    fn handle_line(&mut self, line: &str) -> Result<Option<SocketState>, SocketError> {
+        let response = match serde_json::from_str::<Response>(line) {
+            Ok(response) => response,
+            Err(_) => {
+                // mpv sends other events in the socket that we don't care
+                // about, such as `start-file` or `audio-reconfig`
+                return Ok(None);
+            }
+        };
+
+        match response {
+            Response::PropertyChange(event) => self.handle_property_change(event),
+            Response::Reply(reply) => self.handle_reply(reply),
+            Response::Lifecycle(event) => self.handle_lifecycle_event(event),
+        }
+    }
+
+    fn handle_lifecycle_event(
+        &mut self,
+        event: LifecycleEvent,
+    ) -> Result<Option<SocketState>, SocketError> {
+        let shutdown = matches!(event, LifecycleEvent::Shutdown);
+        let file_loaded = matches!(event, LifecycleEvent::FileLoaded);
+        self.sender
+            .send(Event::Lifecycle(event))
+            .map_err(|_| SocketError::ServerStopped)?;
+
+        // mpv closes the connection after this event, there is no reply or
+        // property change left to wait for
+        if shutdown {
+            return self.close(true);
+        }
+        if file_loaded {
+            return self.request_metadata();
+        }
+        Ok(None)
+    }
+
+    fn handle_property_change(
+        &mut self,
+        event: PropertyChangeEvent,
+    ) -> Result<Option<SocketState>, SocketError> {
+        // Properties observed with `Client::observe` are told apart by their
+        // id, since they may also be observed for the subtitles
+        if let Some(name) = event
+            .id
+            .checked_sub(FIRST_OBSERVED_PROPERTY_ID)
+            .and_then(|index| self.observed_properties.get(index as usize))
+        {
+            self.sender
+                .send(Event::PropertyChange {
+                    name: name.clone(),
+                    data: event.data,
+                })
+                .map_err(|_| SocketError::ServerStopped)?;
+            return Ok(None);
+        }
+
+        let property = match event.name {
+            ObservedProperty::SubText => Property::SubText,
+            ObservedProperty::SecondarySubText => Property::SecondarySubText,
+            ObservedProperty::SubStart => {
+                self.timing.sub_start = event.data.as_f64();
+                return Ok(None);
+            }
+            ObservedProperty::SubEnd => {
+                self.timing.sub_end = event.data.as_f64();
+                return Ok(None);
+            }
+            ObservedProperty::SecondarySubStart => {
+                self.timing.secondary_sub_start = event.data.as_f64();
+                return Ok(None);
+            }
+            ObservedProperty::SecondarySubEnd => {
+                self.timing.secondary_sub_end = event.data.as_f64();
+                return Ok(None);
+            }
+            ObservedProperty::Other => return Ok(None),
+        };
+
+        // mpv omits the data when the property is unavailable
+        let Some(text) = event.data.as_str() else {
+            return Ok(None);
+        };
+        let Some(text) = self.filter.apply(text) else {
+            return Ok(None);
+        };
+
+        let request_id = self.next_request_id();
+        let subtitle = PendingSubtitle {
+            request_id,
+            property,
+            text,
+        };
+        if !self.playback_position {
+            self.send_subtitle(subtitle, None)?;
+            return Ok(None);
+        }
+
+        // The subtitle is held back until mpv replies with the playback
+        // position. By then, mpv has also notified us of the new sub-start and
+        // sub-end values for this subtitle
+        self.pending_subtitles.push_back(subtitle);
+        self.queue_message(
+            format!(
+                "{{\"command\":[\"get_property\",\"time-pos\"],\"request_id\":{request_id}}}\n"
+            )
+            .into(),
+        )
+    }
+
+    fn handle_reply(&mut self, reply: CommandReply) -> Result<Option<SocketState>, SocketError> {
+        if let Some(command) = self.pending_commands.remove(&reply.request_id) {
+            self.sender
+                .send(Event::CommandReply {
+                    client: command.client,
+                    request_id: command.request_id,
+                    error: reply.error,
+                    data: reply.data,
+                })
+                .map_err(|_| SocketError::ServerStopped)?;
+            return Ok(None);
+        }
+
+        if let Some(property) = self.pending_metadata.remove(&reply.request_id) {
+            // Unavailable properties are left as `None`
+            if reply.error == "success" {
+                property.set(&mut self.metadata, reply.data);
+            }
+            // Nothing is playing if mpv does not know the path, which happens
+            // when we connect to an idle mpv
+            if self.pending_metadata.is_empty() && self.metadata.path.is_some() {
+                self.sender
+                    .send(Event::Metadata(std::mem::take(&mut self.metadata)))
+                    .map_err(|_| SocketError::ServerStopped)?;
+            }
+            return Ok(None);
+        }
+
+        let Some(position) = self
+            .pending_subtitles
+            .iter()
+            .position(|subtitle| subtitle.request_id == reply.request_id)
+        else {
+            return Ok(None);
+        };
+        let subtitle = self
+            .pending_subtitles
+            .remove(position)
+            .expect("pending subtitle should exist");
+
+        // time-pos is unavailable when no file is playing
+        let time_pos = if reply.error == "success" {
+            reply.data.as_f64()
+        } else {
+            None
+        };
+        self.send_subtitle(subtitle, time_pos)?;
+
+        Ok(None)
+    }
+
+    fn send_subtitle(
+        &mut self,
+        subtitle: PendingSubtitle,
+        time_pos: Option<f64>,
+    ) -> Result<(), SocketError> {
+        let (sub_start, sub_end) = match subtitle.property {
+            Property::SubText => (self.timing.sub_start, self.timing.sub_end),
+            Property::SecondarySubText => (
+                self.timing.secondary_sub_start,
+                self.timing.secondary_sub_end,
+            ),
+        };
+
+        self.sender
+            .send(Event::Subtitle {
+                property: subtitle.property,
+                text: subtitle.text,
+                sub_start,
+                sub_end,
+                time_pos,
+            })
+            .map_err(|_| SocketError::ServerStopped)
+    }
+
+    fn queue_message(&mut self, message: Arc<str>) -> Result<Option<SocketState>, SocketError> {
+        self.messages.push_back(message);
+
+        if let WriteState::Unwritable = self.write {
+            return Ok(None);
+        }
+
+        self.send_message()
     }
 
     fn send_message(&mut self) -> Result<Option<SocketState>, SocketError> {
         if let Some(msg) = self.messages.pop_front() {
             if let Err(e) = self.stream.write_all(msg.as_bytes()) {
                 match e.kind() {
-                    io::ErrorKind::WriteZero => {
-                        let (sender, _) = mio_channel::sync_channel::<Arc<str>>(1);
-                        let state = std::mem::replace(
-                            self,
-                            ConnectedState {
-                                stream: Box::new(EmptyStream),
-                                messages: VecDeque::new(),
-                                write: WriteState::Unwritable,
-                                sender,
-                            },
-                        );
-                        return Ok(Some(SocketState::Closed(state.stream)));
-                    }
+                    io::ErrorKind::WriteZero => return self.transition_to_closed(),
                     io::ErrorKind::WouldBlock => {
                         self.write = WriteState::Unwritable;
                         self.messages.push_front(msg);
@@ -274,11 +561,84 @@ impl ConnectedState {
 // {"event":"property-change","id":1,"name":"sub-text","data":"hello world"}
 const OBSERVE_PROPERTY_SUB_TEXT: &[u8] = b"{\"command\":[\"observe_property\",1,\"sub-text\"]}\n";
 
+// Both subtitle properties are observed under distinct ids so that the
+// WebSocket server can route them to the clients that subscribed to them
 const OBSERVE_PROPERTY_SECONDARY_SUB_TEXT: &[u8] =
-    b"{\"command\":[\"observe_property\",1,\"secondary-sub-text\"]}\n";
+    b"{\"command\":[\"observe_property\",2,\"secondary-sub-text\"]}\n";
 
-const UTF8_NULL_CHARACTER: u8 = 0;
-const UTF8_NEWLINE_CHARACTER: u8 = b"\n"[0];
+// The timing of the current subtitles, attached to each subtitle we broadcast
+const OBSERVE_PROPERTY_SUB_START: &[u8] = b"{\"command\":[\"observe_property\",3,\"sub-start\"]}\n";
+const OBSERVE_PROPERTY_SUB_END: &[u8] = b"{\"command\":[\"observe_property\",4,\"sub-end\"]}\n";
+const OBSERVE_PROPERTY_SECONDARY_SUB_START: &[u8] =
+    b"{\"command\":[\"observe_property\",5,\"secondary-sub-start\"]}\n";
+const OBSERVE_PROPERTY_SECONDARY_SUB_END: &[u8] =
+    b"{\"command\":[\"observe_property\",6,\"secondary-sub-end\"]}\n";
+
+// The id of the first property observed with `Client::observe`, leaving room
+// for the properties above
+const FIRST_OBSERVED_PROPERTY_ID: u32 = 100;
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// What to do with a response from mpv that is not valid UTF-8, as happens
+/// with subtitle files in another encoding
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidUtf8 {
+    /// Replace the invalid bytes with `�`
+    #[default]
+    Replace,
+    /// Skip the response
+    Skip,
+}
+
+/// How the responses of mpv are decoded
+#[derive(Clone, Copy, Default)]
+struct Decoding {
+    invalid_utf8: InvalidUtf8,
+    /// Whether responses that are not valid UTF-8 are decoded as EUC-JP or
+    /// Shift_JIS if possible
+    charset_fallback: bool,
+}
+
+impl Decoding {
+    /// Decodes `line`, or returns `None` if it is skipped
+    fn decode<'a>(&self, line: &'a [u8]) -> Option<Cow<'a, str>> {
+        if let Ok(line) = std::str::from_utf8(line) {
+            return Some(Cow::Borrowed(line));
+        }
+
+        if self.charset_fallback {
+            // Most EUC-JP bytes are also valid Shift_JIS half-width katakana,
+            // which would decode EUC-JP as garbage, while the bytes of most
+            // Shift_JIS characters are not valid EUC-JP
+            for encoding in [EUC_JP, SHIFT_JIS] {
+                if let Some(line) =
+                    encoding.decode_without_bom_handling_and_without_replacement(line)
+                {
+                    debug!("decoded mpv response as {}", encoding.name());
+                    return Some(line);
+                }
+            }
+        }
+
+        match self.invalid_utf8 {
+            InvalidUtf8::Replace => {
+                warn!("mpv responded with invalid UTF-8, replacing the invalid bytes");
+                Some(String::from_utf8_lossy(line))
+            }
+            InvalidUtf8::Skip => {
+                warn!("skipping mpv response that is not valid UTF-8");
+                None
+            }
+        }
+    }
+}
+
+/// Responses longer than this are skipped, so that a misbehaving peer cannot
+/// make us buffer without limit
+const MAX_RESPONSE_LENGTH: usize = 16 * 1024 * 1024;
 
 #[derive(Deserialize)]
 enum EventType {
@@ -287,11 +647,22 @@ enum EventType {
 }
 
 #[derive(Deserialize)]
-enum Property {
+enum ObservedProperty {
     #[serde(rename = "sub-text")]
     SubText,
     #[serde(rename = "secondary-sub-text")]
     SecondarySubText,
+    #[serde(rename = "sub-start")]
+    SubStart,
+    #[serde(rename = "sub-end")]
+    SubEnd,
+    #[serde(rename = "secondary-sub-start")]
+    SecondarySubStart,
+    #[serde(rename = "secondary-sub-end")]
+    SecondarySubEnd,
+    /// A property observed with [`Client::observe`]
+    #[serde(other)]
+    Other,
 }
 
 #[allow(dead_code)]
@@ -299,144 +670,503 @@ enum Property {
 struct PropertyChangeEvent {
     event: EventType,
     id: u32,
-    name: Property,
-    data: String,
+    name: ObservedProperty,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+// Example response:
+// {"data":12.345,"request_id":1,"error":"success"}
+#[derive(Deserialize)]
+struct CommandReply {
+    request_id: u64,
+    error: String,
+    #[serde(default)]
+    data: serde_json::Value,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Response {
+    PropertyChange(PropertyChangeEvent),
+    Reply(CommandReply),
+    Lifecycle(LifecycleEvent),
+}
+
+/// The properties making up the [`MediaMetadata`] of a file
+#[derive(Clone, Copy)]
+enum MetadataProperty {
+    MediaTitle,
+    Path,
+    Filename,
+    Duration,
+    SubtitleTrack,
+}
+
+impl MetadataProperty {
+    const ALL: [Self; 5] = [
+        Self::MediaTitle,
+        Self::Path,
+        Self::Filename,
+        Self::Duration,
+        Self::SubtitleTrack,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::MediaTitle => "media-title",
+            Self::Path => "path",
+            Self::Filename => "filename",
+            Self::Duration => "duration",
+            Self::SubtitleTrack => "current-tracks/sub",
+        }
+    }
+
+    fn set(self, metadata: &mut MediaMetadata, data: serde_json::Value) {
+        match self {
+            Self::MediaTitle => metadata.media_title = data.as_str().map(str::to_owned),
+            Self::Path => metadata.path = data.as_str().map(str::to_owned),
+            Self::Filename => metadata.filename = data.as_str().map(str::to_owned),
+            Self::Duration => metadata.duration = data.as_f64(),
+            Self::SubtitleTrack => metadata.subtitle_track = (!data.is_null()).then_some(data),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SubtitleTiming {
+    sub_start: Option<f64>,
+    sub_end: Option<f64>,
+    secondary_sub_start: Option<f64>,
+    secondary_sub_end: Option<f64>,
+}
+
+struct PendingSubtitle {
+    request_id: u64,
+    property: Property,
+    text: String,
+}
+
+/// A client of mpv's JSON IPC that forwards the observed subtitles to a
+/// [`websocket::ServerStarted`]
+///
+/// ```no_run
+/// use mpv_websocket::mpv::Client;
+/// use mpv_websocket::websocket::Server;
+///
+/// let server = Server::new("127.0.0.1:6677".parse().unwrap())
+///     .start()
+///     .expect("failed to start the WebSocket server");
+/// Client::new("/tmp/mpv-socket".into())
+///     .poll_and_send_messages_to_server(server)
+///     .expect("failed to poll the mpv socket");
+/// ```
 pub struct Client {
     path: PathBuf,
-    use_secondary_subs: bool,
+    filter: Filter,
+    decoding: Decoding,
+    observed_properties: Arc<[String]>,
+    playback_position: bool,
 }
 
 impl Client {
-    pub fn new(path: PathBuf, use_secondary_subs: bool) -> Self {
+    /// Creates a client for the unix socket or named pipe at `path`, which mpv
+    /// creates with the `--input-ipc-server` option
+    pub fn new(path: PathBuf) -> Self {
         Self {
             path,
-            use_secondary_subs,
+            filter: Filter::default(),
+            decoding: Decoding::default(),
+            observed_properties: Arc::new([]),
+            playback_position: true,
         }
     }
 
-    pub fn poll_and_send_messages_to_server(&mut self, server: websocket::ServerStarted) {
-        let (sender, mut receiver) = mio_channel::sync_channel::<Arc<str>>(10);
+    /// Sets the filter applied to subtitles before they are broadcast
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Observes these mpv properties, such as `media-title` or `pause`, and
+    /// broadcasts their values as [`Event::PropertyChange`] whenever they
+    /// change
+    pub fn observe(mut self, properties: Vec<String>) -> Self {
+        self.observed_properties = properties.into();
+        self
+    }
 
-        let mut poll =
-            Poll::new().unwrap_or_else(|e| panic!("failed to create poll instance: {e:?}"));
-        let mut events = Events::with_capacity(128);
+    /// Sets whether each subtitle waits for mpv to reply with the playback
+    /// position, attached as `time_pos`. Enabled by default, it can be
+    /// disabled when the timings are not used, such as in the plain output
+    /// format, so that subtitles are broadcast without the round trip
+    pub fn playback_position(mut self, playback_position: bool) -> Self {
+        self.playback_position = playback_position;
+        self
+    }
+
+    /// Sets what happens to responses that are not valid UTF-8. Invalid bytes
+    /// are replaced by default
+    pub fn invalid_utf8(mut self, invalid_utf8: InvalidUtf8) -> Self {
+        self.decoding.invalid_utf8 = invalid_utf8;
+        self
+    }
+
+    /// Decodes the responses that are not valid UTF-8 as EUC-JP, then as
+    /// Shift_JIS, before `invalid_utf8` applies, for subtitle files in these
+    /// Japanese encodings
+    pub fn charset_fallback(mut self, charset_fallback: bool) -> Self {
+        self.decoding.charset_fallback = charset_fallback;
+        self
+    }
 
-        #[cfg(unix)]
-        let mut stream = loop {
+    #[cfg(unix)]
+    fn connect(&self) -> Result<Box<dyn Stream>, io::Error> {
+        loop {
             match UnixStream::connect(&self.path) {
-                Ok(stream) => break stream,
+                Ok(stream) => return Ok(Box::new(stream)),
                 // UnixStream::connect may return a WouldBlock in which case the
                 // socket connection cannot be completed immediately. Usually it
                 // means the backlog is full.
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                Err(e) => panic!(
-                    "is mpv running with `--input-ipc-server={}`: {:?}",
-                    self.path
-                        .to_str()
-                        .expect("the socket path should be set by the user"),
-                    e
-                ),
+                Err(e) => return Err(e),
             }
-        };
+        }
+    }
 
-        #[cfg(windows)]
-        let mut stream = create_named_pipe(&self.path).unwrap_or_else(|e| {
-            panic!(
-                "is mpv running with `--input-ipc-server={}`: {:?}",
-                self.path
-                    .to_str()
-                    .expect("the socket path should be set by the user"),
-                e
-            )
-        });
+    #[cfg(windows)]
+    fn connect(&self) -> Result<Box<dyn Stream>, io::Error> {
+        Ok(Box::new(create_named_pipe(&self.path)?))
+    }
 
+    fn register(
+        &self,
+        poll: &Poll,
+        mut stream: Box<dyn Stream>,
+        server: &websocket::ServerStarted,
+    ) -> Result<SocketState, crate::Error> {
         poll.registry()
             .register(
                 &mut stream,
                 CLIENT,
                 Interest::READABLE.add(Interest::WRITABLE),
             )
-            .unwrap_or_else(|e| panic!("failed to register socket client to poll instance: {e:?}"));
-        poll.registry()
-            .register(&mut receiver, BROADCAST, Interest::READABLE)
-            .unwrap_or_else(|e| {
-                panic!("failed to register broadcast channel to poll instance: {e:?}")
-            });
+            .map_err(|e| crate::Error::Mpv(SocketError::Io(e)))?;
 
-        let mut state = SocketState::Connected(ConnectedState {
-            stream: Box::new(stream),
-            messages: VecDeque::new(),
-            write: WriteState::Unwritable,
-            sender,
-        });
-
-        let observe_command = if self.use_secondary_subs {
-            OBSERVE_PROPERTY_SECONDARY_SUB_TEXT
-        } else {
-            OBSERVE_PROPERTY_SUB_TEXT
-        };
+        let mut state = SocketState::Connected(Box::new(ConnectedState::new(
+            stream,
+            server.sender(),
+            self.filter.clone(),
+            self.decoding,
+            self.observed_properties.clone(),
+            self.playback_position,
+        )));
 
+        for observe_command in [
+            OBSERVE_PROPERTY_SUB_TEXT,
+            OBSERVE_PROPERTY_SECONDARY_SUB_TEXT,
+            OBSERVE_PROPERTY_SUB_START,
+            OBSERVE_PROPERTY_SUB_END,
+            OBSERVE_PROPERTY_SECONDARY_SUB_START,
+            OBSERVE_PROPERTY_SECONDARY_SUB_END,
+        ] {
+            state
+                .next_state(SocketMessage::SendText(
+                    std::str::from_utf8(observe_command)
+                        .expect("observe property command should be a valid UTF-8 string")
+                        .into(),
+                ))
+                .unwrap_or_else(|e| panic!("message should not have been sent yet: {e:?}"));
+        }
+        for (id, name) in (FIRST_OBSERVED_PROPERTY_ID..).zip(self.observed_properties.iter()) {
+            let observe_command = serde_json::json!({
+                "command": ["observe_property", id, name],
+            })
+            .to_string()
+                + "\n";
+            state
+                .next_state(SocketMessage::SendText(observe_command.into()))
+                .unwrap_or_else(|e| panic!("message should not have been sent yet: {e:?}"));
+        }
+        // A file may already be playing
         state
-            .next_state(SocketMessage::SendText(
-                std::str::from_utf8(observe_command)
-                    .expect("observe property sub-text command should be a valid UTF-8 string")
-                    .into(),
-            ))
+            .next_state(SocketMessage::RequestMetadata)
             .unwrap_or_else(|e| panic!("message should not have been sent yet: {e:?}"));
 
+        Ok(state)
+    }
+
+    /// Connects to mpv and forwards its events to `server` until an
+    /// unrecoverable error occurs. The connection is re-established whenever
+    /// mpv closes it
+    pub fn poll_and_send_messages_to_server(
+        &mut self,
+        mut server: websocket::ServerStarted,
+    ) -> Result<(), crate::Error> {
+        match self.poll(&mut server) {
+            // The server thread knows why it stopped
+            Err(crate::Error::ServerStopped) => Err(server.stopped()),
+            result => result,
+        }
+    }
+
+    fn poll(&mut self, server: &mut websocket::ServerStarted) -> Result<(), crate::Error> {
+        let mut poll = Poll::new().map_err(crate::Error::Poll)?;
+        let mut events = Events::with_capacity(128);
+
+        poll.registry()
+            .register(server.commands(), COMMANDS, Interest::READABLE)
+            .map_err(crate::Error::Poll)?;
+
+        // The socket is `None` while we are waiting to reconnect to mpv
+        let mut state: Option<SocketState> = None;
+        let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
+        let mut reconnect_at = Instant::now();
+        let mut has_connected = false;
+
         loop {
-            if let Err(e) = poll.poll(&mut events, None) {
+            if state.is_none() && Instant::now() >= reconnect_at {
+                match self.connect() {
+                    Ok(stream) => {
+                        state = Some(self.register(&poll, stream, server)?);
+                        reconnect_delay = INITIAL_RECONNECT_DELAY;
+                        if has_connected {
+                            info!("reconnected to mpv socket at `{}`", self.path.display());
+                        }
+                        has_connected = true;
+                        server.send_event(Event::MpvConnected)?;
+                    }
+                    Err(e) => {
+                        // Only the first failed attempt is worth reporting, mpv
+                        // is usually just restarting or loading the next file
+                        if reconnect_delay == INITIAL_RECONNECT_DELAY {
+                            warn!(
+                                "failed to connect to mpv socket, is mpv running with \
+                                 `--input-ipc-server={}`: {:?}. Retrying until mpv is available.",
+                                self.path.display(),
+                                e
+                            );
+                        }
+                        reconnect_at = Instant::now() + reconnect_delay;
+                        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                    }
+                }
+            }
+
+            // Commands from the WebSocket clients are still answered while we
+            // wait to reconnect
+            let timeout = state
+                .is_none()
+                .then(|| reconnect_at.saturating_duration_since(Instant::now()));
+            if let Err(e) = poll.poll(&mut events, timeout) {
                 if e.kind() == io::ErrorKind::Interrupted {
                     continue;
                 }
-                panic!("failed to poll for events: {e:?}");
+                return Err(crate::Error::Poll(e));
             }
 
             for event in &events {
                 match event.token() {
                     CLIENT => {
+                        // The event may belong to a connection that was
+                        // already closed
+                        let Some(socket) = &mut state else {
+                            continue;
+                        };
+
                         if event.is_readable() {
-                            state
-                                .next_state(SocketMessage::MessagesAvailable)
-                                .unwrap_or_else(|e| {
-                                    panic!("failed to read messages on socket: {e:?}")
-                                });
-                            if let SocketState::Closed(mut stream) = state {
-                                poll.registry().deregister(&mut stream).unwrap_or_else(|e| {
-                                    panic!("failed to deregister stream: {e:?}")
-                                });
-                                return;
+                            if let Err(e) = socket.next_state(SocketMessage::MessagesAvailable) {
+                                close_on_error(socket, e, "failed to read messages on socket")?;
                             }
                         }
 
-                        if event.is_writable() {
-                            state
-                                .next_state(SocketMessage::CanWrite)
-                                .unwrap_or_else(|e| {
-                                    panic!("failed to handle writable event on socket: {e:?}")
-                                });
-                            if let SocketState::Closed(mut stream) = state {
-                                poll.registry().deregister(&mut stream).unwrap_or_else(|e| {
-                                    panic!("failed to deregister stream: {e:?}")
-                                });
-                                return;
+                        if event.is_writable() && matches!(socket, SocketState::Connected(_)) {
+                            if let Err(e) = socket.next_state(SocketMessage::CanWrite) {
+                                close_on_error(
+                                    socket,
+                                    e,
+                                    "failed to handle writable event on socket",
+                                )?;
                             }
                         }
                     }
-                    BROADCAST => {
+                    COMMANDS => {
                         if !event.is_readable() {
                             continue;
                         }
 
-                        if let Ok(msg) = receiver.try_recv() {
-                            server.send_message(msg);
+                        loop {
+                            let command = match server.commands().try_recv() {
+                                Ok(command) => command,
+                                Err(TryRecvError::Empty) => break,
+                                Err(TryRecvError::Disconnected) => {
+                                    return Err(crate::Error::ServerStopped)
+                                }
+                            };
+                            match &mut state {
+                                Some(socket @ SocketState::Connected(_)) => {
+                                    if let Err(e) =
+                                        socket.next_state(SocketMessage::SendCommand(command))
+                                    {
+                                        close_on_error(
+                                            socket,
+                                            e,
+                                            "failed to send command on socket",
+                                        )?;
+                                    }
+                                }
+                                Some(SocketState::Closed { .. }) | None => {
+                                    server.send_event(command.reject("mpv is not connected"))?;
+                                }
+                            }
                         }
                     }
-                    _ => unreachable!("only the client and broadcast channel should be registered"),
+                    _ => unreachable!("only the client and command channel should be registered"),
                 }
             }
+
+            if let Some(SocketState::Closed { stream, shutdown }) = &mut state {
+                poll.registry()
+                    .deregister(stream)
+                    .map_err(|e| crate::Error::Mpv(SocketError::Io(e)))?;
+                if *shutdown {
+                    info!("mpv is shutting down, reconnecting once it restarts");
+                } else {
+                    warn!(
+                        "lost connection to mpv socket at `{}`, reconnecting",
+                        self.path.display()
+                    );
+                }
+                state = None;
+                reconnect_at = Instant::now();
+
+                server.send_event(Event::MpvDisconnected)?;
+            }
+        }
+    }
+}
+
+/// Closes the socket after an error so that the client reconnects to mpv,
+/// unless the error is caused by the WebSocket server stopping
+fn close_on_error(
+    socket: &mut SocketState,
+    error: SocketError,
+    context: &str,
+) -> Result<(), crate::Error> {
+    if let SocketError::ServerStopped = error {
+        return Err(crate::Error::ServerStopped);
+    }
+
+    warn!("{}: {:?}", context, error);
+    socket.close();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::Encoding;
+
+    use super::*;
+
+    const LINE: &str =
+        r#"{"event":"property-change","id":1,"name":"sub-text","data":"今日は晴れ"}"#;
+
+    fn encode(encoding: &'static Encoding, line: &str) -> Vec<u8> {
+        let (bytes, _, unmappable) = encoding.encode(line);
+        assert!(!unmappable);
+        bytes.into_owned()
+    }
+
+    fn decoding(invalid_utf8: InvalidUtf8, charset_fallback: bool) -> Decoding {
+        Decoding {
+            invalid_utf8,
+            charset_fallback,
         }
     }
+
+    #[test]
+    fn decode_borrows_utf8_lines() {
+        for decoding in [
+            decoding(InvalidUtf8::Replace, false),
+            decoding(InvalidUtf8::Skip, true),
+        ] {
+            assert!(matches!(
+                decoding.decode(LINE.as_bytes()),
+                Some(Cow::Borrowed(LINE))
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_falls_back_to_shift_jis() {
+        let line = encode(SHIFT_JIS, LINE);
+        for invalid_utf8 in [InvalidUtf8::Replace, InvalidUtf8::Skip] {
+            assert_eq!(
+                decoding(invalid_utf8, true).decode(&line).as_deref(),
+                Some(LINE)
+            );
+        }
+    }
+
+    #[test]
+    fn decode_falls_back_to_euc_jp() {
+        let line = encode(EUC_JP, LINE);
+        assert_eq!(
+            decoding(InvalidUtf8::Skip, true).decode(&line).as_deref(),
+            Some(LINE)
+        );
+    }
+
+    #[test]
+    fn decode_falls_back_to_shift_jis_for_kana() {
+        for text in ["はい、そうです", "こんにちは", "カタカナ"] {
+            let line = format!(r#"{{"data":"{text}"}}"#);
+            assert_eq!(
+                decoding(InvalidUtf8::Skip, true)
+                    .decode(&encode(SHIFT_JIS, &line))
+                    .as_deref(),
+                Some(line.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn decode_falls_back_to_euc_jp_for_kana() {
+        for text in ["はい、そうです", "こんにちは", "カタカナ"] {
+            let line = format!(r#"{{"data":"{text}"}}"#);
+            assert_eq!(
+                decoding(InvalidUtf8::Skip, true)
+                    .decode(&encode(EUC_JP, &line))
+                    .as_deref(),
+                Some(line.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn decode_replaces_invalid_bytes_without_the_fallback() {
+        let line = encode(SHIFT_JIS, LINE);
+        let decoded = decoding(InvalidUtf8::Replace, false)
+            .decode(&line)
+            .expect("line should be replaced");
+        assert!(decoded.contains('\u{FFFD}'));
+        assert!(decoded.starts_with(r#"{"event":"property-change""#));
+    }
+
+    #[test]
+    fn decode_skips_invalid_lines_without_the_fallback() {
+        let line = encode(SHIFT_JIS, LINE);
+        assert_eq!(decoding(InvalidUtf8::Skip, false).decode(&line), None);
+    }
+
+    #[test]
+    fn decode_applies_the_policy_when_the_fallback_fails() {
+        // Neither UTF-8, Shift_JIS nor EUC-JP
+        let line = b"{\"data\":\"\x80\xff\"}";
+        assert_eq!(decoding(InvalidUtf8::Skip, true).decode(line), None);
+        assert_eq!(
+            decoding(InvalidUtf8::Replace, true).decode(line).as_deref(),
+            Some("{\"data\":\"\u{FFFD}\u{FFFD}\"}")
+        );
+    }
 }
@@ -0,0 +1,151 @@
+//! A hashed timer wheel, so that the poll loops can wait for their next
+//! timeout without keeping their timers sorted
+
+use std::time::{Duration, Instant};
+
+/// Timers scheduled in ticks of `tick`. A timer is stored in the slot of its
+/// tick modulo the number of slots, so timers further away than one rotation
+/// share slots with closer ones and are skipped until their rotation comes
+pub(crate) struct TimerWheel<T> {
+    slots: Vec<Vec<(Instant, T)>>,
+    tick: Duration,
+    start: Instant,
+    /// The last tick whose timers expired
+    current_tick: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub(crate) fn new(tick: Duration, slots: usize) -> Self {
+        Self {
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            tick,
+            start: Instant::now(),
+            current_tick: 0,
+            len: 0,
+        }
+    }
+
+    /// Schedules `value` to expire at `deadline`, or on the next tick if the
+    /// deadline has passed
+    pub(crate) fn schedule(&mut self, deadline: Instant, value: T) {
+        let elapsed = deadline.saturating_duration_since(self.start);
+        // Rounded up so that timers never expire before their deadline
+        let tick = elapsed.as_nanos().div_ceil(self.tick.as_nanos()) as u64;
+        let tick = tick.max(self.current_tick + 1);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((deadline, value));
+        self.len += 1;
+    }
+
+    /// The time until the next tick with timers, or `None` if there are none.
+    /// The timers of that tick may belong to a later rotation, in which case
+    /// the caller wakes up for nothing
+    pub(crate) fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let slots = self.slots.len() as u64;
+        let next_tick = (self.current_tick + 1..=self.current_tick + slots)
+            .find(|tick| !self.slots[(tick % slots) as usize].is_empty())?;
+        let deadline = self.start
+            + Duration::from_nanos((self.tick.as_nanos() as u64).saturating_mul(next_tick));
+        Some(deadline.saturating_duration_since(now))
+    }
+
+    /// Removes and returns the timers whose deadline is at or before `now`
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<T> {
+        let now_tick =
+            (now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos()) as u64;
+        if now_tick <= self.current_tick {
+            return Vec::new();
+        }
+
+        let slots = self.slots.len() as u64;
+        // Every slot is visited at most once, even after a long pause
+        let first_tick = (self.current_tick + 1).max(now_tick.saturating_sub(slots - 1));
+        let mut expired = Vec::new();
+        for tick in first_tick..=now_tick {
+            let slot = &mut self.slots[(tick % slots) as usize];
+            let (due, pending) = std::mem::take(slot)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+            *slot = pending;
+            expired.extend(due.into_iter().map(|(_, value)| value));
+        }
+        self.current_tick = now_tick;
+        self.len -= expired.len();
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(100);
+    const SLOTS: usize = 4;
+
+    fn millis(wheel: &TimerWheel<u32>, millis: u64) -> Instant {
+        wheel.start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn expires_timers_at_their_deadline() {
+        let mut wheel = TimerWheel::new(TICK, SLOTS);
+        wheel.schedule(millis(&wheel, 250), 1);
+        wheel.schedule(millis(&wheel, 100), 2);
+
+        assert_eq!(wheel.next_timeout(millis(&wheel, 0)), Some(TICK));
+        assert_eq!(wheel.expire(millis(&wheel, 50)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(millis(&wheel, 100)), vec![2]);
+        assert_eq!(wheel.expire(millis(&wheel, 200)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(millis(&wheel, 300)), vec![1]);
+        assert_eq!(wheel.next_timeout(millis(&wheel, 300)), None);
+    }
+
+    #[test]
+    fn expires_passed_deadlines_on_the_next_tick() {
+        let mut wheel = TimerWheel::new(TICK, SLOTS);
+        assert!(wheel.expire(millis(&wheel, 250)).is_empty());
+        wheel.schedule(millis(&wheel, 0), 1);
+
+        assert_eq!(wheel.expire(millis(&wheel, 299)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(millis(&wheel, 300)), vec![1]);
+    }
+
+    #[test]
+    fn keeps_timers_of_later_rotations() {
+        let mut wheel = TimerWheel::new(TICK, SLOTS);
+        // Both timers are in the slot of tick 2, one rotation apart
+        wheel.schedule(millis(&wheel, 150), 1);
+        wheel.schedule(millis(&wheel, 550), 2);
+
+        assert_eq!(wheel.expire(millis(&wheel, 200)), vec![1]);
+        // The slot is visited again a rotation later, before the deadline
+        assert_eq!(
+            wheel.next_timeout(millis(&wheel, 200)),
+            Some(Duration::from_millis(400))
+        );
+        assert_eq!(wheel.expire(millis(&wheel, 500)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(millis(&wheel, 599)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(millis(&wheel, 600)), vec![2]);
+        assert_eq!(wheel.next_timeout(millis(&wheel, 600)), None);
+    }
+
+    #[test]
+    fn expires_every_timer_after_a_long_pause() {
+        let mut wheel = TimerWheel::new(TICK, SLOTS);
+        for (value, deadline) in (0..).zip([100, 200, 300, 400, 500, 900, 1300]) {
+            wheel.schedule(millis(&wheel, deadline), value);
+        }
+        wheel.schedule(millis(&wheel, 10_100), 7);
+
+        let mut expired = wheel.expire(millis(&wheel, 10_000));
+        expired.sort_unstable();
+        assert_eq!(expired, (0..7).collect::<Vec<_>>());
+        assert_eq!(wheel.expire(millis(&wheel, 10_100)), vec![7]);
+        assert_eq!(wheel.next_timeout(millis(&wheel, 10_100)), None);
+    }
+}
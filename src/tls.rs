@@ -0,0 +1,414 @@
+//! TLS for the WebSocket server, so that pages served over HTTPS can connect
+//! with `wss://`. Only available with the `tls` feature.
+
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+
+/// An error while loading or generating the TLS certificate
+#[derive(Debug)]
+pub enum TlsError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    InvalidPem {
+        path: PathBuf,
+        source: rustls::pki_types::pem::Error,
+    },
+    InvalidCertificate(rustls::Error),
+    Generate(rcgen::Error),
+    /// Only one of the certificate and the private key exists, so a new pair
+    /// would overwrite it
+    Incomplete {
+        existing: PathBuf,
+        missing: PathBuf,
+    },
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io { path, source } => write!(f, "`{}`: {source}", path.display()),
+            TlsError::InvalidPem { path, source } => {
+                write!(f, "invalid PEM file `{}`: {source}", path.display())
+            }
+            TlsError::InvalidCertificate(error) => write!(f, "invalid certificate: {error}"),
+            TlsError::Generate(error) => {
+                write!(f, "failed to generate self-signed certificate: {error}")
+            }
+            TlsError::Incomplete { existing, missing } => write!(
+                f,
+                "`{}` exists but `{}` does not, remove it to generate a new \
+                 self-signed certificate",
+                existing.display(),
+                missing.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Io { source, .. } => Some(source),
+            TlsError::InvalidPem { source, .. } => Some(source),
+            TlsError::InvalidCertificate(error) => Some(error),
+            TlsError::Generate(error) => Some(error),
+            TlsError::Incomplete { .. } => None,
+        }
+    }
+}
+
+/// Loads the PEM encoded certificate chain and private key used by
+/// [`crate::websocket::Server::tls`]
+pub fn load_server_config(
+    certificate: &Path,
+    private_key: &Path,
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let certificates = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(certificate, e))?;
+    let private_key =
+        PrivateKeyDer::from_pem_file(private_key).map_err(|e| pem_error(private_key, e))?;
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::InvalidCertificate)?
+            .with_no_client_auth()
+            .with_single_cert(certificates, private_key)
+            .map_err(TlsError::InvalidCertificate)?;
+
+    Ok(Arc::new(config))
+}
+
+fn pem_error(path: &Path, error: rustls::pki_types::pem::Error) -> TlsError {
+    match error {
+        rustls::pki_types::pem::Error::Io(source) => TlsError::Io {
+            path: path.to_owned(),
+            source,
+        },
+        source => TlsError::InvalidPem {
+            path: path.to_owned(),
+            source,
+        },
+    }
+}
+
+/// Generates a self-signed certificate for `subject_alt_names` and writes it
+/// and its private key as PEM files, unless both files already exist. Fails
+/// if only one of them exists rather than overwriting it. On unix, the private
+/// key is only readable by its owner. Browsers only accept the certificate
+/// once the user trusts it, for example by opening `https://localhost:6677`
+/// and accepting the warning
+pub fn generate_self_signed_certificate(
+    certificate: &Path,
+    private_key: &Path,
+    subject_alt_names: Vec<String>,
+) -> Result<(), TlsError> {
+    match (certificate.exists(), private_key.exists()) {
+        (true, true) => return Ok(()),
+        (true, false) => {
+            return Err(TlsError::Incomplete {
+                existing: certificate.to_owned(),
+                missing: private_key.to_owned(),
+            })
+        }
+        (false, true) => {
+            return Err(TlsError::Incomplete {
+                existing: private_key.to_owned(),
+                missing: certificate.to_owned(),
+            })
+        }
+        (false, false) => {}
+    }
+
+    let certified_key =
+        rcgen::generate_simple_self_signed(subject_alt_names).map_err(TlsError::Generate)?;
+    for (path, contents, mode) in [
+        (certificate, certified_key.cert.pem(), 0o644),
+        (private_key, certified_key.key_pair.serialize_pem(), 0o600),
+    ] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| TlsError::Io {
+                path: parent.to_owned(),
+                source: e,
+            })?;
+        }
+        write_new_file(path, contents.as_bytes(), mode).map_err(|e| TlsError::Io {
+            path: path.to_owned(),
+            source: e,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Writes a file that must not exist yet, created with `mode` on unix
+fn write_new_file(
+    path: &Path,
+    contents: &[u8],
+    #[cfg_attr(not(unix), allow(unused_variables))] mode: u32,
+) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    options.open(path)?.write_all(contents)
+}
+
+/// A non-blocking TLS session over an accepted connection
+pub(crate) struct TlsStream {
+    connection: ServerConnection,
+    stream: TcpStream,
+}
+
+impl TlsStream {
+    pub(crate) fn new(config: Arc<ServerConfig>, stream: TcpStream) -> io::Result<Self> {
+        let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Self { connection, stream })
+    }
+
+    /// Continues the TLS handshake. Returns whether the stream is ready for
+    /// the WebSocket handshake, that is the TLS handshake is complete and the
+    /// client started sending its HTTP request
+    pub(crate) fn continue_handshake(&mut self) -> io::Result<bool> {
+        self.write_tls_until_blocked()?;
+        self.read_tls()?;
+        Ok(!self.connection.is_handshaking()
+            && self
+                .connection
+                .process_new_packets()
+                .map_err(io::Error::other)?
+                .plaintext_bytes_to_read()
+                > 0)
+    }
+
+    /// Reads the available TLS records until the socket would block
+    fn read_tls(&mut self) -> io::Result<()> {
+        loop {
+            match self.connection.read_tls(&mut self.stream) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {
+                    let result = self.connection.process_new_packets();
+                    // Handshake messages and alerts have to be sent back even
+                    // if the records are invalid
+                    self.write_tls_until_blocked()?;
+                    result.map_err(io::Error::other)?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes the pending TLS records, failing with `WouldBlock` if they could
+    /// not all be written
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            match self.connection.write_tls(&mut self.stream) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the pending TLS records, leaving the rest for the next writable
+    /// event if the socket would block
+    fn write_tls_until_blocked(&mut self) -> io::Result<()> {
+        match self.write_tls() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.connection.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                // The client closed the connection without notifying us
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // No decrypted data is left, so more records are read from the
+            // socket. This only returns `WouldBlock` once the socket is
+            // drained, as mio requires
+            match self.connection.read_tls(&mut self.stream) {
+                Ok(0) => return Ok(0),
+                Ok(_) => {
+                    let result = self.connection.process_new_packets();
+                    self.write_tls_until_blocked()?;
+                    result.map_err(io::Error::other)?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The records of previous writes are sent first, so that a slow client
+        // makes the caller wait instead of growing the buffer of rustls
+        self.write_tls()?;
+        let n = self.connection.writer().write(buf)?;
+        self.write_tls_until_blocked()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.writer().flush()?;
+        self.write_tls()?;
+        self.stream.flush()
+    }
+}
+
+impl Source for TlsStream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.stream.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.stream.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.stream.deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use super::*;
+    use crate::event::{Event, Property};
+    use crate::websocket::Server;
+
+    /// A directory removed when the test ends
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("mpv_websocket-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn unused_address() -> SocketAddr {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("should find an unused port")
+    }
+
+    #[test]
+    fn generate_self_signed_certificate_refuses_to_overwrite_a_lone_file() {
+        let dir = TempDir::new("lone-file");
+        let certificate = dir.0.join("certificate.pem");
+        let private_key = dir.0.join("key.pem");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(&certificate, "existing").unwrap();
+
+        let result =
+            generate_self_signed_certificate(&certificate, &private_key, vec!["localhost".into()]);
+
+        assert!(matches!(result, Err(TlsError::Incomplete { .. })));
+        assert_eq!(fs::read_to_string(&certificate).unwrap(), "existing");
+        assert!(!private_key.exists());
+    }
+
+    #[test]
+    fn wss_client_receives_broadcasts() {
+        let dir = TempDir::new("loopback");
+        let certificate = dir.0.join("certificate.pem");
+        let private_key = dir.0.join("key.pem");
+        generate_self_signed_certificate(&certificate, &private_key, vec!["localhost".into()])
+            .expect("certificate should be generated");
+
+        let address = unused_address();
+        let server = Server::new(address)
+            .tls(load_server_config(&certificate, &private_key).expect("config should load"))
+            .start()
+            .expect("server should start");
+
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(&certificate).unwrap() {
+            roots.add(certificate.unwrap()).unwrap();
+        }
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let connection =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let stream = std::net::TcpStream::connect(address).expect("should connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mut websocket, _) = tungstenite::client(
+            format!("wss://localhost:{}/", address.port()),
+            StreamOwned::new(connection, stream),
+        )
+        .expect("handshake should succeed");
+        // The server registers the client on its next wakeup
+        thread::sleep(Duration::from_millis(100));
+
+        server
+            .send_event(Event::Subtitle {
+                property: Property::SubText,
+                text: "line".to_owned(),
+                sub_start: None,
+                sub_end: None,
+                time_pos: None,
+            })
+            .expect("server should be running");
+        match websocket.read().expect("should receive the line") {
+            tungstenite::Message::Text(text) => assert_eq!(text.as_str(), "line"),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+}
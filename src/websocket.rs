@@ -1,22 +1,55 @@
+use clap::ValueEnum;
 use mio::event::Source;
 use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Registry, Token};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt::Display;
 use std::io::{self, Read, Write};
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::mpsc::TrySendError;
 use std::sync::Arc;
-use std::thread;
-use tracing::warn;
-use tungstenite::protocol::Role;
-use tungstenite::WebSocket;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::header::{AUTHORIZATION, ORIGIN, SEC_WEBSOCKET_PROTOCOL, WWW_AUTHENTICATE};
+use tungstenite::http::{HeaderValue, Method, StatusCode, Uri};
+use tungstenite::{HandshakeError, WebSocket};
 
-use crate::mio_channel::{self, SyncSender};
+use crate::command::{ClientId, Command};
+use crate::event::{Event, LifecycleEvent, OutputFormat, Property};
+use crate::http::{self, ClientQueueStatus, RequestHead, RewindStream, ServerStatus};
+use crate::mio_channel::{self, Overflow, SyncSender};
+use crate::timer::TimerWheel;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
 
 const SERVER: Token = Token(0);
 const BROADCAST: Token = Token(SERVER.0 + 1);
 
+/// The precision of the keepalive timers
+const TIMER_TICK: Duration = Duration::from_millis(100);
+const TIMER_SLOTS: usize = 512;
+
+/// The number of events waiting to be broadcast beyond which the oldest are
+/// dropped, so that the mpv connection never waits for a stalled server
+const EVENT_QUEUE_SIZE: u32 = 1024;
+
+/// The longest keepalive interval or timeout, beyond which they are clamped so
+/// that huge values cannot overflow the clock
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When `timeout` after `instant` has passed
+fn deadline(instant: Instant, timeout: Duration) -> Instant {
+    instant
+        .checked_add(timeout.min(MAX_TIMEOUT))
+        .unwrap_or(instant)
+}
+
 trait TokenExt {
     fn next(&self) -> Self;
 }
@@ -27,10 +60,97 @@ impl TokenExt for Token {
     }
 }
 
-trait Stream: Read + Write + Source {}
+trait Stream: Read + Write + Source {
+    /// Whether the connection is ready to be upgraded to a WebSocket after
+    /// `event`, that is the client started sending its handshake request
+    fn is_ready(&mut self, event: &mio::event::Event) -> io::Result<bool> {
+        Ok(event.is_readable())
+    }
+}
 
 impl Stream for TcpStream {}
 
+#[cfg(feature = "tls")]
+impl Stream for TlsStream {
+    fn is_ready(&mut self, _: &mio::event::Event) -> io::Result<bool> {
+        self.continue_handshake()
+    }
+}
+
+/// What a timer of the server is for
+#[derive(Clone, Copy)]
+enum Timer {
+    /// The connection must have sent its request by now, or finished its TLS
+    /// handshake
+    Request(Token),
+    KeepAlive(Token),
+}
+
+/// A connection whose request has not been received in full yet, after which
+/// it is either upgraded to a WebSocket or answered as a plain HTTP request
+struct PendingConnection {
+    stream: Box<dyn Stream>,
+    request: Vec<u8>,
+}
+
+impl PendingConnection {
+    /// Reads the request received so far and returns its head once complete
+    fn read_request(&mut self, event: &mio::event::Event) -> io::Result<Option<RequestHead>> {
+        if !self.stream.is_ready(event)? {
+            return Ok(None);
+        }
+
+        let mut buffer = [0; 4096];
+        while self.request.len() < http::MAX_REQUEST_HEAD_SIZE {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.request.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        http::parse_request_head(&self.request)
+    }
+}
+
+impl Stream for RewindStream<Box<dyn Stream>> {}
+
+/// Starts writing `response`, which is written on the next writable events if
+/// the socket would block. The connection is closed once it is written
+fn start_http_response(
+    token: Token,
+    mut stream: Box<dyn Stream>,
+    mut response: http::Response,
+    token_to_http_responses: &mut HashMap<Token, (Box<dyn Stream>, http::Response)>,
+    registry: &Registry,
+) {
+    match response.write_to(&mut stream) {
+        Ok(false) => {
+            token_to_http_responses.insert(token, (stream, response));
+        }
+        Ok(true) | Err(_) => {
+            if let Err(e) = registry.deregister(&mut stream) {
+                warn!("failed to deregister stream for token {:?}: {:?}", token, e);
+            }
+        }
+    }
+}
+
+/// Wraps an accepted connection in a TLS session if the server uses TLS
+fn wrap_stream(
+    stream: TcpStream,
+    #[cfg(feature = "tls")] tls: Option<&Arc<rustls::ServerConfig>>,
+) -> io::Result<Box<dyn Stream>> {
+    #[cfg(feature = "tls")]
+    if let Some(config) = tls {
+        return Ok(Box::new(TlsStream::new(config.clone(), stream)?));
+    }
+
+    Ok(Box::new(stream))
+}
+
 struct EmptyStream;
 
 impl Stream for EmptyStream {}
@@ -65,25 +185,29 @@ impl Source for EmptyStream {
     }
 }
 
+/// An error on the WebSocket server or one of its connections
 #[derive(Debug)]
-enum WebSocketError {
+pub enum WebSocketError {
     Io(io::Error),
-    Handshake(
-        tungstenite::HandshakeError<
-            tungstenite::ServerHandshake<
-                Box<dyn Stream>,
-                tungstenite::handshake::server::NoCallback,
-            >,
-        >,
-    ),
-    WebSocket(tungstenite::Error),
+    /// The listener failed to accept a new connection
+    Accept(io::Error),
+    Handshake(Box<tungstenite::Error>),
+    HandshakeInterrupted,
+    /// The client was refused with this HTTP status during the handshake
+    Rejected(StatusCode),
+    WebSocket(Box<tungstenite::Error>),
 }
 
 impl Display for WebSocketError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             WebSocketError::Io(error) => write!(f, "IO error: {error}"),
+            WebSocketError::Accept(error) => write!(f, "failed to accept connection: {error}"),
             WebSocketError::Handshake(error) => write!(f, "handshake error: {error}"),
+            WebSocketError::HandshakeInterrupted => {
+                write!(f, "handshake error: the request was not received in full")
+            }
+            WebSocketError::Rejected(status) => write!(f, "client rejected with {status}"),
             WebSocketError::WebSocket(error) => write!(f, "WebSocket error: {error}"),
         }
     }
@@ -93,7 +217,9 @@ impl Error for WebSocketError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             WebSocketError::Io(error) => Some(error),
+            WebSocketError::Accept(error) => Some(error),
             WebSocketError::Handshake(error) => Some(error),
+            WebSocketError::HandshakeInterrupted | WebSocketError::Rejected(_) => None,
             WebSocketError::WebSocket(error) => Some(error),
         }
     }
@@ -105,31 +231,9 @@ impl From<io::Error> for WebSocketError {
     }
 }
 
-impl
-    From<
-        tungstenite::HandshakeError<
-            tungstenite::ServerHandshake<
-                Box<dyn Stream>,
-                tungstenite::handshake::server::NoCallback,
-            >,
-        >,
-    > for WebSocketError
-{
-    fn from(
-        value: tungstenite::HandshakeError<
-            tungstenite::ServerHandshake<
-                Box<dyn Stream>,
-                tungstenite::handshake::server::NoCallback,
-            >,
-        >,
-    ) -> Self {
-        Self::Handshake(value)
-    }
-}
-
 impl From<tungstenite::Error> for WebSocketError {
     fn from(value: tungstenite::Error) -> Self {
-        Self::WebSocket(value)
+        Self::WebSocket(Box::new(value))
     }
 }
 
@@ -138,12 +242,20 @@ enum WebSocketMessage {
     MessagesAvailable,
     CanWrite,
     SendText(Arc<str>),
+    /// A subtitle, which [`SlowClientPolicy::CoalesceLatest`] replaces with
+    /// newer ones
+    SendSubtitle(Arc<str>),
+    /// The keepalive timer of the connection expired
+    KeepAlive(Instant),
 }
 
 enum WebSocketState {
     Unconnected(UnconnectedState),
-    Connected(ConnectedState),
-    Closed(WebSocket<Box<dyn Stream>>),
+    Connected(Box<ConnectedState>),
+    /// A Server-Sent Events client, which receives the same messages as the
+    /// WebSocket clients but cannot send any
+    EventStream(EventStreamState),
+    Closed(Box<dyn Stream>),
 }
 
 impl WebSocketState {
@@ -155,33 +267,145 @@ impl WebSocketState {
                     *self = state;
                 }
             }
+            WebSocketState::EventStream(state) => {
+                if let Some(state) = state.next_state(message)? {
+                    *self = state;
+                }
+            }
             WebSocketState::Closed(_) => {
-                eprintln!("WebSocket is already closed");
                 warn!("WebSocket is already closed");
             }
         }
 
         Ok(())
     }
+
+    /// Takes the text messages received from the client since the last call
+    fn take_requests(&mut self) -> Vec<String> {
+        match self {
+            WebSocketState::Connected(state) => std::mem::take(&mut state.requests),
+            WebSocketState::Unconnected(_)
+            | WebSocketState::EventStream(_)
+            | WebSocketState::Closed(_) => Vec::new(),
+        }
+    }
+
+    fn is_subscribed_to(&self, event: &Event) -> bool {
+        match self {
+            WebSocketState::Connected(state) => state.options.includes(event),
+            WebSocketState::EventStream(state) => state.options.includes(event),
+            WebSocketState::Unconnected(_) | WebSocketState::Closed(_) => true,
+        }
+    }
+
+    /// The number of messages waiting to be sent to a client and the number
+    /// of messages dropped because it did not keep up
+    fn queue_status(&self) -> Option<(usize, u64)> {
+        match self {
+            WebSocketState::Connected(state) => {
+                Some((state.messages.messages.len(), state.messages.dropped))
+            }
+            WebSocketState::EventStream(state) => {
+                Some((state.messages.messages.len(), state.messages.dropped))
+            }
+            WebSocketState::Unconnected(_) | WebSocketState::Closed(_) => None,
+        }
+    }
+
+    /// When the keepalive timer of the connection should next expire
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        match self {
+            WebSocketState::Connected(state) => state.keepalive_deadline(),
+            WebSocketState::EventStream(state) => state.keepalive_deadline(),
+            WebSocketState::Unconnected(_) | WebSocketState::Closed(_) => None,
+        }
+    }
+
+    /// The number of recent subtitles the client asked for when it connected
+    fn requested_history(&self) -> usize {
+        match self {
+            WebSocketState::Connected(state) => state.options.requested_history,
+            WebSocketState::EventStream(state) => state.options.requested_history,
+            WebSocketState::Unconnected(_) | WebSocketState::Closed(_) => 0,
+        }
+    }
 }
 
-struct UnconnectedState;
+struct UnconnectedState {
+    token: Token,
+    default_subscription: Subscription,
+    output_format: OutputFormat,
+    auth_token: Option<Arc<str>>,
+    allowed_origins: Arc<[String]>,
+    keepalive: Option<KeepAlive>,
+    max_queued_messages: NonZeroUsize,
+    slow_client_policy: SlowClientPolicy,
+}
 
 impl UnconnectedState {
     fn next_state(&mut self, message: WebSocketMessage) -> Result<WebSocketState, WebSocketError> {
         match message {
             WebSocketMessage::UpgradeWebSocket(stream) => {
-                Ok(WebSocketState::Connected(ConnectedState {
-                    websocket: tungstenite::accept(stream)?,
-                    messages: VecDeque::new(),
+                let mut options = ClientOptions::new(self.default_subscription, self.output_format);
+                // The error response type is dictated by tungstenite
+                #[allow(clippy::result_large_err)]
+                let callback = |request: &Request, mut response: Response| {
+                    let auth_token = self.auth_token.as_deref();
+                    if let Some(Credentials::Protocol) =
+                        authorize(request, auth_token, &self.allowed_origins)?
+                    {
+                        // Browsers close the connection unless the server
+                        // accepts one of the requested subprotocols
+                        if let Some(Ok(protocol)) = auth_token.map(HeaderValue::from_str) {
+                            response
+                                .headers_mut()
+                                .insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+                        }
+                    }
+
+                    options =
+                        client_options(request, self.default_subscription, self.output_format);
+                    Ok(response)
+                };
+
+                let websocket = match tungstenite::accept_hdr(stream, callback) {
+                    Ok(websocket) => websocket,
+                    Err(HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+                        return Err(WebSocketError::Rejected(response.status()))
+                    }
+                    Err(HandshakeError::Failure(e)) => {
+                        return Err(WebSocketError::Handshake(Box::new(e)))
+                    }
+                    Err(HandshakeError::Interrupted(_)) => {
+                        return Err(WebSocketError::HandshakeInterrupted)
+                    }
+                };
+
+                Ok(WebSocketState::Connected(Box::new(ConnectedState {
+                    websocket,
+                    messages: SendQueue::new(
+                        self.token,
+                        self.max_queued_messages,
+                        self.slow_client_policy,
+                    ),
                     write: WriteState::Unwritable,
-                }))
+                    options,
+                    requests: Vec::new(),
+                    keepalive: self.keepalive,
+                    last_received: Instant::now(),
+                    ping_sent: None,
+                })))
             }
             WebSocketMessage::MessagesAvailable => {
                 panic!("messages available on an unconnected WebSocket")
             }
             WebSocketMessage::CanWrite => panic!("writable event on an unconnected WebSocket"),
-            WebSocketMessage::SendText(_) => panic!("text sent on an unconnected WebSocket"),
+            WebSocketMessage::SendText(_) | WebSocketMessage::SendSubtitle(_) => {
+                panic!("text sent on an unconnected WebSocket")
+            }
+            WebSocketMessage::KeepAlive(_) => {
+                panic!("keepalive timer expired on an unconnected WebSocket")
+            }
         }
     }
 }
@@ -191,23 +415,164 @@ enum WriteState {
     Writable,
 }
 
+/// What to do with the messages of a WebSocket client whose queue is full,
+/// because it does not read them as fast as they are sent, for example a
+/// stalled browser tab
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowClientPolicy {
+    /// Drop the oldest queued message to make room for the new one
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Replace the queued subtitles with the latest one, keeping the other
+    /// messages
+    CoalesceLatest,
+    /// Close the connection
+    Disconnect,
+}
+
+impl Display for SlowClientPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlowClientPolicy::DropOldest => write!(f, "drop-oldest"),
+            SlowClientPolicy::DropNewest => write!(f, "drop-newest"),
+            SlowClientPolicy::CoalesceLatest => write!(f, "coalesce-latest"),
+            SlowClientPolicy::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+/// A message waiting to be sent to a client
+struct QueuedMessage {
+    text: Arc<str>,
+    subtitle: bool,
+}
+
+/// The messages waiting to be sent to a WebSocket or event stream client, of
+/// which there are never more than `limit`
+struct SendQueue {
+    /// The token of the client, to identify it in the logs
+    token: Token,
+    messages: VecDeque<QueuedMessage>,
+    limit: NonZeroUsize,
+    policy: SlowClientPolicy,
+    /// The number of messages dropped since the client connected
+    dropped: u64,
+    /// Whether messages were dropped since the queue was last empty
+    dropping: bool,
+}
+
+impl SendQueue {
+    fn new(token: Token, limit: NonZeroUsize, policy: SlowClientPolicy) -> Self {
+        Self {
+            token,
+            messages: VecDeque::new(),
+            limit,
+            policy,
+            dropped: 0,
+            dropping: false,
+        }
+    }
+
+    /// Queues `text`, applying the policy if the queue is full. Returns
+    /// `false` if the client should be disconnected
+    fn push(&mut self, text: Arc<str>, subtitle: bool) -> bool {
+        if self.messages.len() < self.limit.get() {
+            self.messages.push_back(QueuedMessage { text, subtitle });
+            return true;
+        }
+
+        if self.policy == SlowClientPolicy::Disconnect {
+            warn!(
+                "closing connection with token {:?} that did not read its last {} messages, dropping its {} unsent messages",
+                self.token,
+                self.limit,
+                self.messages.len() + 1
+            );
+            return false;
+        }
+        if !self.dropping {
+            self.dropping = true;
+            warn!(
+                "client with token {:?} is not reading its messages fast enough, dropping messages with the {} policy",
+                self.token, self.policy
+            );
+        }
+
+        let queued = self.messages.len();
+        match self.policy {
+            SlowClientPolicy::DropOldest => {
+                self.messages.pop_front();
+            }
+            SlowClientPolicy::DropNewest => {
+                self.dropped += 1;
+                return true;
+            }
+            SlowClientPolicy::CoalesceLatest => {
+                // The latest subtitle is either the new message or the last
+                // queued one
+                let latest_subtitle = if subtitle {
+                    None
+                } else {
+                    self.messages.iter().rposition(|message| message.subtitle)
+                };
+                let mut index = 0;
+                self.messages.retain(|message| {
+                    let keep = !message.subtitle || latest_subtitle == Some(index);
+                    index += 1;
+                    keep
+                });
+                // Only other messages are queued
+                if self.messages.len() == queued {
+                    self.messages.pop_front();
+                }
+            }
+            SlowClientPolicy::Disconnect => unreachable!("disconnected before dropping"),
+        }
+        self.dropped += (queued - self.messages.len()) as u64;
+        self.messages.push_back(QueuedMessage { text, subtitle });
+        true
+    }
+
+    fn pop_front(&mut self) -> Option<Arc<str>> {
+        let message = self.messages.pop_front()?;
+        if self.messages.is_empty() && self.dropping {
+            self.dropping = false;
+            info!(
+                "client with token {:?} caught up, {} messages were dropped since it connected",
+                self.token, self.dropped
+            );
+        }
+        Some(message.text)
+    }
+}
+
+/// How long a client can stay silent before it is pinged, and how long it has
+/// to answer before the connection is considered dead
+#[derive(Clone, Copy)]
+struct KeepAlive {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+}
+
 struct ConnectedState {
     websocket: WebSocket<Box<dyn Stream>>,
-    messages: VecDeque<Arc<str>>,
+    messages: SendQueue,
     write: WriteState,
+    options: ClientOptions,
+    requests: Vec<String>,
+    keepalive: Option<KeepAlive>,
+    /// When the last frame was received from the client
+    last_received: Instant,
+    /// When the ping the client has not answered yet was sent
+    ping_sent: Option<Instant>,
 }
 
 impl ConnectedState {
     fn transition_to_closed(&mut self) -> Result<Option<WebSocketState>, WebSocketError> {
-        let state = std::mem::replace(
-            self,
-            ConnectedState {
-                websocket: WebSocket::from_raw_socket(Box::new(EmptyStream), Role::Server, None),
-                messages: VecDeque::new(),
-                write: WriteState::Unwritable,
-            },
-        );
-        Ok(Some(WebSocketState::Closed(state.websocket)))
+        let stream = std::mem::replace(self.websocket.get_mut(), Box::new(EmptyStream));
+        Ok(Some(WebSocketState::Closed(stream)))
     }
 
     fn next_state(
@@ -219,8 +584,18 @@ impl ConnectedState {
                 panic!("connection is already upgraded to a WebSocket")
             }
             WebSocketMessage::MessagesAvailable => loop {
-                match self.websocket.read() {
-                    Ok(msg) => msg,
+                let message = self.websocket.read();
+                if message.is_ok() {
+                    // Any frame, not only a pong, shows that the client is
+                    // still there
+                    self.last_received = Instant::now();
+                    self.ping_sent = None;
+                }
+                match message {
+                    Ok(tungstenite::Message::Text(text)) => {
+                        self.requests.push(text.as_str().to_owned())
+                    }
+                    Ok(_) => {}
                     Err(e) => match e {
                         tungstenite::Error::ConnectionClosed
                         | tungstenite::Error::Protocol(
@@ -235,9 +610,6 @@ impl ConnectedState {
                             io::ErrorKind::Interrupted => continue,
                             io::ErrorKind::ConnectionReset => return self.transition_to_closed(),
                             _ => {
-                                eprintln!(
-                                    "unhandled websocket read io error, closing connection: {e}"
-                                );
                                 warn!(
                                     "unhandled websocket read io error, closing connection: {}",
                                     e
@@ -246,7 +618,6 @@ impl ConnectedState {
                             }
                         },
                         _ => {
-                            eprintln!("unhandled websocket read error, closing connection: {e}");
                             warn!("unhandled websocket read error, closing connection: {}", e);
                             return self.transition_to_closed();
                         }
@@ -258,23 +629,83 @@ impl ConnectedState {
                     self.write = WriteState::Writable;
                 }
 
-                // On write events, we send one message at a time because mio
-                // will send another write event after each successful flush.
-                // This will allow us to drain our internal buffer if there are
-                // any remaining messages left. It also allows the caller to
-                // respond to each message since it is done one at a time
-                self.send_message()
-            }
-            WebSocketMessage::SendText(message) => {
-                self.messages.push_back(message);
+                // The frame that could not be written in full is kept by
+                // tungstenite until the next write or flush
+                if let Err(e) = self.websocket.flush() {
+                    return self.handle_write_error(e);
+                }
 
-                if let WriteState::Unwritable = self.write {
+                self.send_messages()
+            }
+            WebSocketMessage::SendText(message) => self.queue_message(message, false),
+            WebSocketMessage::SendSubtitle(message) => self.queue_message(message, true),
+            WebSocketMessage::KeepAlive(now) => {
+                let Some(keepalive) = self.keepalive else {
                     return Ok(None);
+                };
+
+                match self.ping_sent {
+                    Some(ping_sent) if now >= deadline(ping_sent, keepalive.pong_timeout) => {
+                        info!(
+                            "closing WebSocket that did not answer a ping within {:?}",
+                            keepalive.pong_timeout
+                        );
+                        self.transition_to_closed()
+                    }
+                    None if now >= deadline(self.last_received, keepalive.ping_interval) => {
+                        self.ping_sent = Some(now);
+                        // Pings are sent ahead of the queued messages
+                        match self
+                            .websocket
+                            .send(tungstenite::Message::Ping(Vec::new().into()))
+                        {
+                            Ok(()) => Ok(None),
+                            Err(e) => self.handle_write_error(e),
+                        }
+                    }
+                    _ => Ok(None),
                 }
+            }
+        }
+    }
+
+    /// When the next ping should be sent, or when the connection should be
+    /// closed if the client has not answered the last one
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let keepalive = self.keepalive?;
+        Some(match self.ping_sent {
+            Some(ping_sent) => deadline(ping_sent, keepalive.pong_timeout),
+            None => deadline(self.last_received, keepalive.ping_interval),
+        })
+    }
+
+    fn queue_message(
+        &mut self,
+        message: Arc<str>,
+        subtitle: bool,
+    ) -> Result<Option<WebSocketState>, WebSocketError> {
+        if !self.messages.push(message, subtitle) {
+            return self.transition_to_closed();
+        }
+        self.send_messages()
+    }
 
-                self.send_message()
+    /// Sends the buffered messages until the socket would block. mio only
+    /// delivers another write event once the socket becomes writable again,
+    /// so anything left in the buffer would otherwise wait for the next
+    /// message
+    fn send_messages(&mut self) -> Result<Option<WebSocketState>, WebSocketError> {
+        while let WriteState::Writable = self.write {
+            if self.messages.messages.is_empty() {
+                break;
+            }
+
+            if let Some(state) = self.send_message()? {
+                return Ok(Some(state));
             }
         }
+
+        Ok(None)
     }
 
     fn send_message(&mut self) -> Result<Option<WebSocketState>, WebSocketError> {
@@ -283,106 +714,805 @@ impl ConnectedState {
                 .websocket
                 .send(tungstenite::Message::Text((*msg).into()))
             {
-                match e {
-                    tungstenite::Error::ConnectionClosed
-                    | tungstenite::Error::Protocol(
-                        tungstenite::error::ProtocolError::ResetWithoutClosingHandshake
-                        | tungstenite::error::ProtocolError::InvalidCloseSequence
-                        | tungstenite::error::ProtocolError::UnmaskedFrameFromClient,
-                    ) => {
-                        return self.transition_to_closed();
-                    }
-                    tungstenite::Error::Io(ref err) => match err.kind() {
-                        // On write error, tungstenite will store the frame in
-                        // its internal buffer and send it on a subsequent call
-                        // to write or flush. Hence, we do not need to push the
-                        // message back into our buffer here
-                        io::ErrorKind::WouldBlock => self.write = WriteState::Unwritable,
+                return self.handle_write_error(e);
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn handle_write_error(
+        &mut self,
+        e: tungstenite::Error,
+    ) -> Result<Option<WebSocketState>, WebSocketError> {
+        match e {
+            tungstenite::Error::ConnectionClosed
+            | tungstenite::Error::Protocol(
+                tungstenite::error::ProtocolError::ResetWithoutClosingHandshake
+                | tungstenite::error::ProtocolError::InvalidCloseSequence
+                | tungstenite::error::ProtocolError::UnmaskedFrameFromClient,
+            ) => {
+                return self.transition_to_closed();
+            }
+            tungstenite::Error::Io(ref err) => match err.kind() {
+                // On write error, tungstenite will store the frame in its
+                // internal buffer and send it on a subsequent call to write or
+                // flush. Hence, we do not need to push the message back into
+                // our buffer here
+                io::ErrorKind::WouldBlock => self.write = WriteState::Unwritable,
+                io::ErrorKind::Interrupted => {}
+                io::ErrorKind::ConnectionReset => return self.transition_to_closed(),
+                _ => {
+                    warn!(
+                        "unhandled websocket write io error, closing connection: {}",
+                        e
+                    );
+                    return self.transition_to_closed();
+                }
+            },
+            _ => {
+                warn!("unhandled websocket write error, closing connection: {}", e);
+                return self.transition_to_closed();
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// A client of the `/events` endpoint, to which the messages are sent as
+/// Server-Sent Events over a never ending HTTP response
+struct EventStreamState {
+    stream: Box<dyn Stream>,
+    /// The bytes of the response head or of the event being written
+    buffer: Vec<u8>,
+    messages: SendQueue,
+    options: ClientOptions,
+    keepalive: Option<KeepAlive>,
+    /// When bytes were last written to the client
+    last_written: Instant,
+    /// When the comment the client has not read yet was sent
+    comment_sent: Option<Instant>,
+}
+
+impl EventStreamState {
+    /// Creates the client of an authorized request. The response headers are
+    /// sent on the first writable event
+    fn new(
+        stream: Box<dyn Stream>,
+        request: &Request,
+        default_subscription: Subscription,
+        output_format: OutputFormat,
+        messages: SendQueue,
+        keepalive: Option<KeepAlive>,
+    ) -> Self {
+        let options = client_options(request, default_subscription, output_format);
+        let mut head = String::from(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/event-stream; charset=utf-8\r\n\
+             Cache-Control: no-store\r\n",
+        );
+        // The origin was checked against the allowed origins, so that pages of
+        // other origins can read the response
+        if let Some(origin) = request.headers().get(ORIGIN).and_then(|v| v.to_str().ok()) {
+            head.push_str(&format!("Access-Control-Allow-Origin: {origin}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        Self {
+            stream,
+            buffer: head.into_bytes(),
+            messages,
+            options,
+            keepalive,
+            last_written: Instant::now(),
+            comment_sent: None,
+        }
+    }
+
+    fn transition_to_closed(&mut self) -> Result<Option<WebSocketState>, WebSocketError> {
+        let stream = std::mem::replace(&mut self.stream, Box::new(EmptyStream));
+        Ok(Some(WebSocketState::Closed(stream)))
+    }
+
+    fn next_state(
+        &mut self,
+        message: WebSocketMessage,
+    ) -> Result<Option<WebSocketState>, WebSocketError> {
+        match message {
+            WebSocketMessage::UpgradeWebSocket(_) => {
+                panic!("event stream cannot be upgraded to a WebSocket")
+            }
+            // The client cannot send anything once the request is received,
+            // so reading only tells whether it closed the connection
+            WebSocketMessage::MessagesAvailable => loop {
+                let mut buffer = [0; 1024];
+                match self.stream.read(&mut buffer) {
+                    Ok(0) => return self.transition_to_closed(),
+                    Ok(_) => {}
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::WouldBlock => return Ok(None),
                         io::ErrorKind::Interrupted => {}
                         io::ErrorKind::ConnectionReset => return self.transition_to_closed(),
                         _ => {
-                            eprintln!(
-                                "unhandled websocket write io error, closing connection: {e}"
-                            );
                             warn!(
-                                "unhandled websocket write io error, closing connection: {}",
+                                "unhandled event stream read io error, closing connection: {}",
                                 e
                             );
                             return self.transition_to_closed();
                         }
                     },
+                }
+            },
+            WebSocketMessage::CanWrite => self.write_buffer(),
+            WebSocketMessage::SendText(message) => self.queue_message(message, false),
+            WebSocketMessage::SendSubtitle(message) => self.queue_message(message, true),
+            WebSocketMessage::KeepAlive(now) => {
+                let Some(keepalive) = self.keepalive else {
+                    return Ok(None);
+                };
+
+                match self.comment_sent {
+                    Some(comment_sent) if now >= deadline(comment_sent, keepalive.pong_timeout) => {
+                        info!(
+                            "closing event stream that did not read a comment within {:?}",
+                            keepalive.pong_timeout
+                        );
+                        self.transition_to_closed()
+                    }
+                    None if now >= deadline(self.last_written, keepalive.ping_interval) => {
+                        self.comment_sent = Some(now);
+                        // Clients ignore comments, which are only sent so that
+                        // writing to a client that disappeared fails
+                        self.buffer.extend_from_slice(b":\n\n");
+                        self.write_buffer()
+                    }
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// When the next comment should be sent, or when the connection should be
+    /// closed if the client has not read the last one
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let keepalive = self.keepalive?;
+        Some(match self.comment_sent {
+            Some(comment_sent) => deadline(comment_sent, keepalive.pong_timeout),
+            None => deadline(self.last_written, keepalive.ping_interval),
+        })
+    }
+
+    fn queue_message(
+        &mut self,
+        message: Arc<str>,
+        subtitle: bool,
+    ) -> Result<Option<WebSocketState>, WebSocketError> {
+        if !self.messages.push(message, subtitle) {
+            return self.transition_to_closed();
+        }
+        self.write_buffer()
+    }
+
+    /// Writes the queued events until the socket would block. Each event is
+    /// only taken from the queue once the previous one is written, so that a
+    /// client that does not read them is limited like WebSocket clients
+    fn write_buffer(&mut self) -> Result<Option<WebSocketState>, WebSocketError> {
+        loop {
+            if self.buffer.is_empty() {
+                let Some(message) = self.messages.pop_front() else {
+                    // Everything was written, including the last comment
+                    self.comment_sent = None;
+                    break;
+                };
+                // Every line of the message is a `data` field of the event
+                for line in message.split('\n') {
+                    self.buffer.extend_from_slice(b"data: ");
+                    self.buffer
+                        .extend_from_slice(line.strip_suffix('\r').unwrap_or(line).as_bytes());
+                    self.buffer.push(b'\n');
+                }
+                self.buffer.push(b'\n');
+            }
+
+            match self.stream.write(&self.buffer) {
+                Ok(0) => return self.transition_to_closed(),
+                Ok(n) => {
+                    self.buffer.drain(..n);
+                    self.last_written = Instant::now();
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(None),
+                    io::ErrorKind::Interrupted => {}
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {
+                        return self.transition_to_closed()
+                    }
                     _ => {
-                        eprintln!("unhandled websocket write error, closing connection: {e}");
-                        warn!("unhandled websocket write error, closing connection: {}", e);
+                        warn!(
+                            "unhandled event stream write io error, closing connection: {}",
+                            e
+                        );
                         return self.transition_to_closed();
                     }
-                }
+                },
             }
         }
 
-        Ok(None)
+        // Sends the records buffered by TLS
+        match self.stream.flush() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => self.transition_to_closed(),
+            _ => Ok(None),
+        }
     }
 }
 
-pub struct Server {
-    address: SocketAddr,
+/// Which subtitles a WebSocket client receives. Clients choose with the
+/// `subtitles` query parameter on the handshake URL, for example
+/// `ws://localhost:6677/?subtitles=primary,secondary`
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subscription {
+    /// Only `sub-text`
+    Primary,
+    /// Only `secondary-sub-text`
+    Secondary,
+    /// Both `sub-text` and `secondary-sub-text`
+    Both,
 }
 
-pub struct ServerStarted {
-    sender: SyncSender<Arc<str>>,
+impl Subscription {
+    fn parse(value: &str) -> Option<Self> {
+        let mut primary = false;
+        let mut secondary = false;
+        for subtitles in value.split(',') {
+            match subtitles.trim() {
+                "primary" => primary = true,
+                "secondary" => secondary = true,
+                "both" => {
+                    primary = true;
+                    secondary = true;
+                }
+                _ => return None,
+            }
+        }
+
+        match (primary, secondary) {
+            (true, true) => Some(Self::Both),
+            (true, false) => Some(Self::Primary),
+            (false, true) => Some(Self::Secondary),
+            (false, false) => None,
+        }
+    }
+
+    fn includes(&self, event: &Event) -> bool {
+        match event {
+            Event::Subtitle { property, .. } => matches!(
+                (self, property),
+                (Self::Both, _)
+                    | (Self::Primary, Property::SubText)
+                    | (Self::Secondary, Property::SecondarySubText)
+            ),
+            Event::MpvConnected
+            | Event::MpvDisconnected
+            | Event::PropertyChange { .. }
+            | Event::Lifecycle(_)
+            | Event::Metadata(_)
+            | Event::CommandReply { .. } => true,
+        }
+    }
 }
 
-impl Server {
-    pub fn new(address: SocketAddr) -> Self {
-        Self { address }
+/// The messages a client receives
+#[derive(Clone, Copy)]
+struct ClientOptions {
+    subscription: Subscription,
+    /// The number of recent subtitles sent when the client connects
+    requested_history: usize,
+    /// Whether the client receives the notifications, such as
+    /// `mpv-connected`, besides the subtitles. Clients of the plain output
+    /// format are texthooker pages unless they ask for them with the `events`
+    /// query parameter, which would show the notifications as subtitles
+    notifications: bool,
+}
+
+impl ClientOptions {
+    fn new(default_subscription: Subscription, output_format: OutputFormat) -> Self {
+        Self {
+            subscription: default_subscription,
+            requested_history: 0,
+            notifications: output_format == OutputFormat::Json,
+        }
     }
 
-    pub fn start(self) -> ServerStarted {
-        let (sender, mut receiver) = mio_channel::sync_channel::<Arc<str>>(10);
-        let mut poll =
-            Poll::new().unwrap_or_else(|e| panic!("failed to create poll instance: {e:?}"));
-        let mut events = Events::with_capacity(128);
+    fn includes(&self, event: &Event) -> bool {
+        match event {
+            Event::MpvConnected
+            | Event::MpvDisconnected
+            | Event::PropertyChange { .. }
+            | Event::Lifecycle(_)
+            | Event::Metadata(_) => self.notifications,
+            _ => self.subscription.includes(event),
+        }
+    }
+}
 
-        let mut server = TcpListener::bind(self.address)
-            .unwrap_or_else(|e| panic!("failed to bind address `{}`: {:?}", self.address, e));
+/// The number of recent subtitles a client asked to receive on connect with the
+/// `history` query parameter, either `history`, `history=true`, `history=all`
+/// for the whole history or `history=<count>` for the last `count` subtitles
+fn parse_requested_history(value: &str) -> usize {
+    match value {
+        "" | "true" | "all" => usize::MAX,
+        count => count.parse().unwrap_or(0),
+    }
+}
 
-        poll.registry()
-            .register(&mut server, SERVER, Interest::READABLE)
-            .unwrap_or_else(|e| panic!("failed to register server to poll instance: {e:?}"));
-        poll.registry()
-            .register(&mut receiver, BROADCAST, Interest::READABLE)
-            .unwrap_or_else(|e| {
-                panic!("failed to register broadcast channel to poll instance: {e:?}")
-            });
+/// A ring buffer of the most recently broadcast subtitles, replayed to clients
+/// that ask for it when they connect
+struct History {
+    lines: VecDeque<(Event, Arc<str>)>,
+    capacity: usize,
+}
 
-        thread::spawn(move || {
-            let mut token_to_tcpstreams = HashMap::new();
-            let mut token_to_websockets: HashMap<Token, WebSocketState> = HashMap::new();
-            let mut unique_token = Token(BROADCAST.0);
+impl History {
+    /// The lines grow up to `capacity` instead of being allocated upfront,
+    /// since the capacity is given by the user
+    fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            capacity,
+        }
+    }
 
-            loop {
-                if let Err(e) = poll.poll(&mut events, None) {
-                    if e.kind() == io::ErrorKind::Interrupted {
-                        continue;
-                    }
-                    panic!("failed to poll for events: {e:?}");
-                }
+    fn push(&mut self, event: &Event, msg: &Arc<str>) {
+        if self.capacity == 0 || !matches!(event, Event::Subtitle { .. }) {
+            return;
+        }
 
-                for event in &events {
-                    match event.token() {
-                        SERVER => {
-                            if !event.is_readable() {
-                                continue;
-                            }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back((event.clone(), msg.clone()));
+    }
 
-                            loop {
+    fn replay(&self, token: Token, state: &mut WebSocketState) {
+        let requested = state.requested_history();
+        if requested == 0 {
+            return;
+        }
+
+        let lines = self
+            .lines
+            .iter()
+            .filter(|(event, _)| state.is_subscribed_to(event))
+            .collect::<Vec<_>>();
+        for (_, msg) in &lines[lines.len().saturating_sub(requested)..] {
+            if let Err(e) = state.next_state(WebSocketMessage::SendSubtitle(msg.clone())) {
+                warn!(
+                    "failed to replay history to WebSocket with token {:?}: {:?}",
+                    token, e
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Where a client sent the token that authenticated it
+enum Credentials {
+    /// The `token` query parameter, for example `ws://localhost:6677/?token=secret`
+    Query,
+    /// One of the values of the `Sec-WebSocket-Protocol` header, which
+    /// browsers set with the `protocols` argument of the `WebSocket`
+    /// constructor
+    Protocol,
+    /// The `Authorization: Bearer <token>` header
+    Authorization,
+}
+
+/// Checks the origin and the token of a request, returning the error response
+/// to reject it with
+// The error response type is dictated by tungstenite
+#[allow(clippy::result_large_err)]
+fn authorize(
+    request: &Request,
+    auth_token: Option<&str>,
+    allowed_origins: &[String],
+) -> Result<Option<Credentials>, ErrorResponse> {
+    if let Some(origin) = request.headers().get(ORIGIN) {
+        let origin = origin.to_str().unwrap_or_default();
+        if !allowed_origins.is_empty()
+            && !allowed_origins
+                .iter()
+                .any(|allowed| origin_matches(allowed, origin))
+        {
+            warn!("rejected connection from origin `{origin}`");
+            return Err(forbidden());
+        }
+    }
+
+    match auth_token {
+        Some(token) => match authenticate(request, token) {
+            Some(credentials) => Ok(Some(credentials)),
+            None => Err(unauthorized()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// What a client asks for with the `subtitles`, `history` and `events` query
+/// parameters
+fn client_options(
+    request: &Request,
+    default_subscription: Subscription,
+    output_format: OutputFormat,
+) -> ClientOptions {
+    let mut options = ClientOptions::new(default_subscription, output_format);
+    for (key, value) in query_parameters(request.uri()) {
+        match key {
+            "subtitles" => {
+                if let Some(requested) = Subscription::parse(value) {
+                    options.subscription = requested;
+                }
+            }
+            "history" => options.requested_history = parse_requested_history(value),
+            "events" => options.notifications = true,
+            _ => {}
+        }
+    }
+    options
+}
+
+fn authenticate(request: &Request, token: &str) -> Option<Credentials> {
+    if query_parameters(request.uri())
+        .filter(|(key, _)| *key == "token")
+        .filter_map(|(_, value)| http::percent_decode(value))
+        .any(|value| tokens_match(&value, token))
+    {
+        return Some(Credentials::Query);
+    }
+
+    if request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| tokens_match(protocol.trim(), token))
+    {
+        return Some(Credentials::Protocol);
+    }
+
+    if request
+        .headers()
+        .get_all(AUTHORIZATION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.strip_prefix("Bearer "))
+        .any(|bearer| tokens_match(bearer.trim(), token))
+    {
+        return Some(Credentials::Authorization);
+    }
+
+    None
+}
+
+/// Compares the tokens in constant time, so that the time taken to reject a
+/// client does not tell how much of its token is correct
+fn tokens_match(received: &str, expected: &str) -> bool {
+    received.len() == expected.len()
+        && received
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn unauthorized() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("missing or invalid token".to_owned()));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+/// Whether `origin` matches the allowed origin `pattern`, in which `*` matches
+/// any characters, for example `https://*.example.com` or `http://localhost:*`
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let origin = origin.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = origin.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // There is no wildcard
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+fn forbidden() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("origin not allowed".to_owned()));
+    *response.status_mut() = StatusCode::FORBIDDEN;
+    response
+}
+
+fn query_parameters(uri: &Uri) -> impl Iterator<Item = (&str, &str)> {
+    uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .map(|parameter| parameter.split_once('=').unwrap_or((parameter, "")))
+}
+
+/// A WebSocket server that broadcasts mpv events to its clients
+///
+/// ```no_run
+/// use mpv_websocket::event::OutputFormat;
+/// use mpv_websocket::websocket::Server;
+///
+/// let server = Server::new("127.0.0.1:6677".parse().unwrap())
+///     .output_format(OutputFormat::Json)
+///     .start()
+///     .expect("failed to start the WebSocket server");
+/// ```
+pub struct Server {
+    address: SocketAddr,
+    output_format: OutputFormat,
+    default_subscription: Subscription,
+    history_size: usize,
+    auth_token: Option<Arc<str>>,
+    allowed_origins: Arc<[String]>,
+    static_dir: Option<PathBuf>,
+    keepalive: Option<KeepAlive>,
+    /// How long a connection has to finish its TLS handshake and send its
+    /// request
+    request_timeout: Duration,
+    max_queued_messages: NonZeroUsize,
+    slow_client_policy: SlowClientPolicy,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// Parses the commands received from a client and forwards them to the mpv
+/// client, replying straight away to the ones that cannot be forwarded
+fn forward_requests(token: Token, state: &mut WebSocketState, commands: &SyncSender<Command>) {
+    for request in state.take_requests() {
+        let reply = match Command::parse(ClientId(token.0), &request) {
+            // The WebSocket thread must never block on the mpv client, which
+            // may be busy reconnecting
+            Ok(command) => match commands.try_send(command) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(command)) => command.reject("too many pending commands"),
+                Err(TrySendError::Disconnected(command)) => {
+                    command.reject("mpv client is not running")
+                }
+            },
+            Err(reply) => reply,
+        };
+
+        if let Err(e) = state.next_state(WebSocketMessage::SendText(
+            reply.render(OutputFormat::Json, 0),
+        )) {
+            warn!(
+                "failed to send command reply to WebSocket with token {:?}: {:?}",
+                token, e
+            );
+        }
+    }
+}
+
+/// A handle to a running [`Server`], used to send it the events to broadcast
+pub struct ServerStarted {
+    sender: SyncSender<Event>,
+    commands: mio_channel::Receiver<Command>,
+    thread: JoinHandle<Result<(), crate::Error>>,
+}
+
+impl Server {
+    /// Creates a server listening on `address`, sending plain text primary
+    /// subtitles, keeping the last 100 subtitles for clients asking for them,
+    /// pinging clients that stay silent for 30 seconds, closing connections
+    /// that do not send their request within 10 seconds and queuing up to 1000
+    /// messages for slow clients before dropping the oldest
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            output_format: OutputFormat::Plain,
+            default_subscription: Subscription::Primary,
+            history_size: 100,
+            auth_token: None,
+            allowed_origins: Arc::new([]),
+            static_dir: None,
+            keepalive: Some(KeepAlive {
+                ping_interval: Duration::from_secs(30),
+                pong_timeout: Duration::from_secs(10),
+            }),
+            request_timeout: Duration::from_secs(10),
+            max_queued_messages: NonZeroUsize::new(1000).expect("1000 should not be zero"),
+            slow_client_policy: SlowClientPolicy::DropOldest,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Sets the format of the messages sent to the clients
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.output_format = output_format;
+        self
+    }
+
+    /// Sets the subtitles sent to clients that do not use the `subtitles`
+    /// query parameter
+    pub fn default_subscription(mut self, default_subscription: Subscription) -> Self {
+        self.default_subscription = default_subscription;
+        self
+    }
+
+    /// Sets the number of recent subtitles kept for clients that use the
+    /// `history` query parameter
+    pub fn history_size(mut self, history_size: usize) -> Self {
+        self.history_size = history_size;
+        self
+    }
+
+    /// Requires clients to send `token` when they connect, either as the
+    /// `token` query parameter, as a `Sec-WebSocket-Protocol` value or in an
+    /// `Authorization: Bearer` header. Other clients are rejected with
+    /// `401 Unauthorized`
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Only accepts browser clients whose page has one of these origins, in
+    /// which `*` matches any characters, for example `https://*.example.com`.
+    /// Other pages are rejected with `403 Forbidden`. Clients that do not send
+    /// an `Origin` header, which browsers always do, are not checked. Every
+    /// origin is allowed if the list is empty, the default
+    pub fn allowed_origins(mut self, allowed_origins: Vec<String>) -> Self {
+        self.allowed_origins = allowed_origins.into();
+        self
+    }
+
+    /// Serves the files of `static_dir` to plain HTTP requests instead of the
+    /// bundled texthooker page, with `index.html` for directories
+    pub fn static_dir(mut self, static_dir: PathBuf) -> Self {
+        self.static_dir = Some(static_dir);
+        self
+    }
+
+    /// Pings the WebSocket clients that sent nothing for `ping_interval`, and
+    /// closes the connection of the ones that do not answer within
+    /// `pong_timeout`, such as clients whose computer went to sleep. `/events`
+    /// clients that were sent nothing for `ping_interval` are sent a comment
+    /// instead, and closed if they do not read it within `pong_timeout`. A
+    /// zero `ping_interval` disables the pings. Both are clamped to
+    /// [`MAX_TIMEOUT`]
+    pub fn keepalive(mut self, ping_interval: Duration, pong_timeout: Duration) -> Self {
+        self.keepalive = (!ping_interval.is_zero()).then_some(KeepAlive {
+            ping_interval,
+            pong_timeout,
+        });
+        self
+    }
+
+    /// Closes the connections that do not finish their TLS handshake and send
+    /// their request within `request_timeout`, whether or not the pings are
+    /// enabled. Clamped to [`MAX_TIMEOUT`]
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the number of messages queued for a WebSocket client that does not
+    /// read them as fast as they are sent, beyond which `slow_client_policy`
+    /// applies
+    pub fn max_queued_messages(mut self, max_queued_messages: NonZeroUsize) -> Self {
+        self.max_queued_messages = max_queued_messages;
+        self
+    }
+
+    /// Sets what happens to the messages of a WebSocket client whose queue is
+    /// full
+    pub fn slow_client_policy(mut self, slow_client_policy: SlowClientPolicy) -> Self {
+        self.slow_client_policy = slow_client_policy;
+        self
+    }
+
+    /// Serves the clients over TLS, so that they connect with `wss://`. See
+    /// [`crate::tls::load_server_config`]
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Binds the address and starts accepting clients on a background thread
+    pub fn start(self) -> Result<ServerStarted, crate::Error> {
+        let (sender, mut receiver) = mio_channel::sync_channel_with_overflow::<Event>(
+            EVENT_QUEUE_SIZE,
+            Overflow::DropOldest,
+        );
+        let (command_sender, commands) = mio_channel::sync_channel::<Command>(10);
+        let output_format = self.output_format;
+        let default_subscription = self.default_subscription;
+        let auth_token = self.auth_token;
+        let allowed_origins = self.allowed_origins;
+        let static_dir = self.static_dir;
+        let keepalive = self.keepalive;
+        let request_timeout = self.request_timeout;
+        let max_queued_messages = self.max_queued_messages;
+        let slow_client_policy = self.slow_client_policy;
+        let mut history = History::new(self.history_size);
+        // The last metadata broadcast, while its file is playing
+        let mut metadata: Option<(Event, Arc<str>)> = None;
+        #[cfg(feature = "tls")]
+        let tls = self.tls;
+        let mut poll = Poll::new().map_err(crate::Error::Poll)?;
+        let mut events = Events::with_capacity(128);
+
+        let mut server = TcpListener::bind(self.address).map_err(|e| crate::Error::Bind {
+            address: self.address,
+            source: e,
+        })?;
+
+        poll.registry()
+            .register(&mut server, SERVER, Interest::READABLE)
+            .map_err(crate::Error::Poll)?;
+        poll.registry()
+            .register(&mut receiver, BROADCAST, Interest::READABLE)
+            .map_err(crate::Error::Poll)?;
+
+        let thread = thread::spawn(move || -> Result<(), crate::Error> {
+            let mut token_to_pending_connections: HashMap<Token, PendingConnection> =
+                HashMap::new();
+            let mut token_to_websockets: HashMap<Token, WebSocketState> = HashMap::new();
+            let mut token_to_http_responses: HashMap<Token, (Box<dyn Stream>, http::Response)> =
+                HashMap::new();
+            let mut status = ServerStatus::new();
+            // Every pending connection has a request timer, and every connected
+            // client one keepalive timer, rescheduled when it expires
+            let mut timers = TimerWheel::new(TIMER_TICK, TIMER_SLOTS);
+            let mut unique_token = Token(BROADCAST.0);
+            // Sequence number of the last broadcast message so that clients
+            // can detect gaps or reordering
+            let mut seq: u64 = 0;
+
+            loop {
+                if let Err(e) = poll.poll(&mut events, timers.next_timeout(Instant::now())) {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(crate::Error::Poll(e));
+                }
+
+                for event in &events {
+                    match event.token() {
+                        SERVER => {
+                            if !event.is_readable() {
+                                continue;
+                            }
+
+                            loop {
                                 let (mut stream, address) = match server.accept() {
                                     Ok((stream, address)) => (stream, address),
                                     Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                                         break;
                                     }
                                     Err(e) => {
-                                        panic!("failed to accept connection: {e:?}");
+                                        return Err(WebSocketError::Accept(e).into());
                                     }
                                 };
 
@@ -392,9 +1522,6 @@ impl Server {
                                     unique_token,
                                     Interest::READABLE.add(Interest::WRITABLE),
                                 ) {
-                                    eprintln!(
-                                        "failed to register incoming connection `{address}` for events: {e:?}. Connection closed."
-                                    );
                                     warn!(
                                         "failed to register incoming connection `{}` for events: {:?}. Connection closed.",
                                         address, e
@@ -402,7 +1529,31 @@ impl Server {
                                     continue;
                                 }
 
-                                token_to_tcpstreams.insert(unique_token, stream);
+                                let stream = match wrap_stream(
+                                    stream,
+                                    #[cfg(feature = "tls")]
+                                    tls.as_ref(),
+                                ) {
+                                    Ok(stream) => stream,
+                                    Err(e) => {
+                                        warn!(
+                                            "failed to set up incoming connection `{}`: {:?}. Connection closed.",
+                                            address, e
+                                        );
+                                        continue;
+                                    }
+                                };
+                                token_to_pending_connections.insert(
+                                    unique_token,
+                                    PendingConnection {
+                                        stream,
+                                        request: Vec::new(),
+                                    },
+                                );
+                                timers.schedule(
+                                    deadline(Instant::now(), request_timeout),
+                                    Timer::Request(unique_token),
+                                );
                             }
                         }
                         BROADCAST => {
@@ -410,14 +1561,57 @@ impl Server {
                                 continue;
                             }
 
-                            if let Ok(msg) = receiver.try_recv() {
+                            // The senders may have sent several events since
+                            // the last wakeup
+                            for event in receiver.try_iter() {
+                                // Command replies are only sent to the client
+                                // that sent the command
+                                let recipient = match &event {
+                                    Event::CommandReply { client, .. } => Some(Token(client.0)),
+                                    Event::MpvConnected => {
+                                        status.mpv_connected = true;
+                                        seq += 1;
+                                        None
+                                    }
+                                    Event::MpvDisconnected => {
+                                        status.mpv_connected = false;
+                                        seq += 1;
+                                        None
+                                    }
+                                    _ => {
+                                        seq += 1;
+                                        None
+                                    }
+                                };
+                                let msg = event.render(output_format, seq);
+                                history.push(&event, &msg);
+                                match &event {
+                                    Event::Metadata(_) => {
+                                        metadata = Some((event.clone(), msg.clone()))
+                                    }
+                                    Event::Lifecycle(LifecycleEvent::EndFile { .. })
+                                    | Event::MpvDisconnected => metadata = None,
+                                    _ => {}
+                                }
+                                let is_subtitle = matches!(event, Event::Subtitle { .. });
                                 let mut closed_connection_tokens = Vec::new();
                                 for (token, state) in &mut token_to_websockets {
-                                    if let Err(e) =
-                                        state.next_state(WebSocketMessage::SendText(msg.clone()))
+                                    if recipient.is_some_and(|recipient| recipient != *token)
+                                        || !state.is_subscribed_to(&event)
                                     {
-                                        eprintln!("failed to send text `{msg}` to WebSocket with token {token:?}: {e:?}. Connection will be closed.");
-                                        warn!("failed to send text `{}` to WebSocket with token {:?}: {:?}. Connection will be closed.", msg, token, e);
+                                        continue;
+                                    }
+
+                                    let message = if is_subtitle {
+                                        WebSocketMessage::SendSubtitle(msg.clone())
+                                    } else {
+                                        WebSocketMessage::SendText(msg.clone())
+                                    };
+                                    match state.next_state(message) {
+                                        Ok(()) => status.messages_sent += 1,
+                                        Err(e) => {
+                                            warn!("failed to send text `{}` to WebSocket with token {:?}: {:?}. Connection will be closed.", msg, token, e);
+                                        }
                                     }
                                     if let WebSocketState::Closed(_) = state {
                                         closed_connection_tokens.push(*token);
@@ -431,10 +1625,7 @@ impl Server {
                                     let WebSocketState::Closed(mut stream) = state else {
                                         panic!("all WebSocket connections should be closed");
                                     };
-                                    if let Err(e) = poll.registry().deregister(stream.get_mut()) {
-                                        eprintln!(
-                                            "failed to deregister stream for token {token:?}: {e:?}"
-                                        );
+                                    if let Err(e) = poll.registry().deregister(&mut stream) {
                                         warn!(
                                             "failed to deregister stream for token {:?}: {:?}",
                                             token, e
@@ -444,65 +1635,188 @@ impl Server {
                             }
                         }
                         token => {
-                            if event.is_readable() {
-                                if let Some(stream) = token_to_tcpstreams.remove(&token) {
-                                    let mut state = WebSocketState::Unconnected(UnconnectedState);
+                            if let Some(pending) = token_to_pending_connections.get_mut(&token) {
+                                let request = match pending.read_request(event) {
+                                    Ok(Some(request)) => request,
+                                    Ok(None) => continue,
+                                    Err(e) => {
+                                        // Clients probing whether the port is
+                                        // open connect without sending anything
+                                        if e.kind() != io::ErrorKind::UnexpectedEof {
+                                            warn!("failed to read request for token {:?}: {:?}. Connection closed.", token, e);
+                                        }
+                                        if let Some(mut pending) =
+                                            token_to_pending_connections.remove(&token)
+                                        {
+                                            if let Err(e) =
+                                                poll.registry().deregister(&mut pending.stream)
+                                            {
+                                                warn!("failed to deregister stream for token {:?}: {:?}", token, e);
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                };
+                                let pending = token_to_pending_connections
+                                    .remove(&token)
+                                    .expect("pending connection should not have been removed yet");
+
+                                let request_path = request.request.uri().path();
+                                let mut state = if request.is_websocket_upgrade {
+                                    let mut state = WebSocketState::Unconnected(UnconnectedState {
+                                        token,
+                                        default_subscription,
+                                        output_format,
+                                        auth_token: auth_token.clone(),
+                                        allowed_origins: allowed_origins.clone(),
+                                        keepalive,
+                                        max_queued_messages,
+                                        slow_client_policy,
+                                    });
                                     if let Err(e) = state.next_state(
-                                        WebSocketMessage::UpgradeWebSocket(Box::new(stream)),
+                                        WebSocketMessage::UpgradeWebSocket(Box::new(
+                                            RewindStream::new(pending.request, pending.stream),
+                                        )),
                                     ) {
-                                        eprintln!("failed to upgrade tcp stream to WebSocket for token {token:?}: {e:?}. Connection closed.");
                                         warn!("failed to upgrade tcp stream to WebSocket for token {:?}: {:?}. Connection closed.", token, e);
                                         continue;
                                     }
+                                    state
+                                } else if request_path == "/events"
+                                    && request.request.method() == Method::GET
+                                {
+                                    if let Err(response) = authorize(
+                                        &request.request,
+                                        auth_token.as_deref(),
+                                        &allowed_origins,
+                                    ) {
+                                        let response = http::Response::new(
+                                            response.status(),
+                                            "text/plain",
+                                            response.into_body().unwrap_or_default(),
+                                        );
+                                        start_http_response(
+                                            token,
+                                            pending.stream,
+                                            response,
+                                            &mut token_to_http_responses,
+                                            poll.registry(),
+                                        );
+                                        continue;
+                                    }
+
+                                    let mut state =
+                                        WebSocketState::EventStream(EventStreamState::new(
+                                            pending.stream,
+                                            &request.request,
+                                            default_subscription,
+                                            output_format,
+                                            SendQueue::new(
+                                                token,
+                                                max_queued_messages,
+                                                slow_client_policy,
+                                            ),
+                                            keepalive,
+                                        ));
+                                    // The writable event of the connection
+                                    // was already delivered
+                                    if let Err(e) = state.next_state(WebSocketMessage::CanWrite) {
+                                        warn!(
+                                            "failed to start event stream with token {:?}: {:?}",
+                                            token, e
+                                        );
+                                    }
+                                    state
+                                } else {
+                                    status.clients = token_to_websockets.len();
+                                    status.event_queue = receiver.stats();
+                                    status.client_queues = token_to_websockets
+                                        .iter()
+                                        .filter_map(|(token, state)| {
+                                            let (queued, dropped) = state.queue_status()?;
+                                            Some(ClientQueueStatus {
+                                                id: token.0,
+                                                queued,
+                                                dropped,
+                                            })
+                                        })
+                                        .collect();
+                                    let response = http::respond(
+                                        &request.request,
+                                        &status,
+                                        static_dir.as_deref(),
+                                    );
+                                    start_http_response(
+                                        token,
+                                        pending.stream,
+                                        response,
+                                        &mut token_to_http_responses,
+                                        poll.registry(),
+                                    );
+                                    continue;
+                                };
+
+                                // Clients connecting while a file plays
+                                // learn what it is before anything else
+                                if let Some((_, msg)) = metadata
+                                    .as_ref()
+                                    .filter(|(event, _)| state.is_subscribed_to(event))
+                                {
+                                    if let Err(e) =
+                                        state.next_state(WebSocketMessage::SendText(msg.clone()))
+                                    {
+                                        warn!("failed to send metadata to WebSocket with token {:?}: {:?}", token, e);
+                                    }
+                                }
+                                history.replay(token, &mut state);
+
+                                // There is no guarantee that another readiness
+                                // event will be delivered until the readiness
+                                // event has been drained
+                                if let Err(e) =
+                                    state.next_state(WebSocketMessage::MessagesAvailable)
+                                {
+                                    warn!("failed to read messages on new WebSocket with token {:?}: {:?}", token, e);
+                                }
+                                forward_requests(token, &mut state, &command_sender);
 
-                                    // There is no guarantee that another
-                                    // readiness event will be delivered
-                                    // until the readiness event has been
-                                    // drained
+                                if let WebSocketState::Closed(mut stream) = state {
+                                    if let Err(e) = poll.registry().deregister(&mut stream) {
+                                        warn!(
+                                            "failed to deregister stream for token {:?}: {:?}",
+                                            token, e
+                                        );
+                                    }
+                                } else {
+                                    if let Some(deadline) = state.keepalive_deadline() {
+                                        timers.schedule(deadline, Timer::KeepAlive(token));
+                                    }
+                                    token_to_websockets.insert(token, state);
+                                }
+                            } else if event.is_readable() {
+                                let mut needs_removal = false;
+                                if let Some(state) = token_to_websockets.get_mut(&token) {
                                     if let Err(e) =
                                         state.next_state(WebSocketMessage::MessagesAvailable)
                                     {
-                                        eprintln!("failed to read messages on new WebSocket with token {token:?}: {e:?}");
-                                        warn!("failed to read messages on new WebSocket with token {:?}: {:?}", token, e);
+                                        warn!("failed to read messages on WebSocket with token {:?}: {:?}", token, e);
                                     }
+                                    forward_requests(token, state, &command_sender);
+                                    if matches!(state, WebSocketState::Closed(_)) {
+                                        needs_removal = true;
+                                    }
+                                }
 
-                                    if let WebSocketState::Closed(mut stream) = state {
-                                        if let Err(e) = poll.registry().deregister(stream.get_mut())
-                                        {
-                                            eprintln!("failed to deregister stream for token {token:?}: {e:?}");
+                                if needs_removal {
+                                    if let Some(WebSocketState::Closed(mut stream)) =
+                                        token_to_websockets.remove(&token)
+                                    {
+                                        if let Err(e) = poll.registry().deregister(&mut stream) {
                                             warn!(
                                                 "failed to deregister stream for token {:?}: {:?}",
                                                 token, e
                                             );
                                         }
-                                    } else {
-                                        token_to_websockets.insert(token, state);
-                                    }
-                                } else {
-                                    let mut needs_removal = false;
-                                    if let Some(state) = token_to_websockets.get_mut(&token) {
-                                        if let Err(e) =
-                                            state.next_state(WebSocketMessage::MessagesAvailable)
-                                        {
-                                            eprintln!("failed to read messages on WebSocket with token {token:?}: {e:?}");
-                                            warn!("failed to read messages on WebSocket with token {:?}: {:?}", token, e);
-                                        }
-                                        if matches!(state, WebSocketState::Closed(_)) {
-                                            needs_removal = true;
-                                        }
-                                    }
-
-                                    if needs_removal {
-                                        if let Some(WebSocketState::Closed(mut stream)) =
-                                            token_to_websockets.remove(&token)
-                                        {
-                                            if let Err(e) =
-                                                poll.registry().deregister(stream.get_mut())
-                                            {
-                                                eprintln!("failed to deregister stream for token {token:?}: {e:?}");
-                                                warn!("failed to deregister stream for token {:?}: {:?}", token, e);
-                                            }
-                                        }
                                     }
                                 }
                             }
@@ -511,7 +1825,6 @@ impl Server {
                                 let mut needs_removal = false;
                                 if let Some(state) = token_to_websockets.get_mut(&token) {
                                     if let Err(e) = state.next_state(WebSocketMessage::CanWrite) {
-                                        eprintln!("failed to handle writable event on WebSocket with token {token:?}: {e:?}");
                                         warn!("failed to handle writable event on WebSocket with token {:?}: {:?}", token, e);
                                     }
                                     if matches!(state, WebSocketState::Closed(_)) {
@@ -523,11 +1836,7 @@ impl Server {
                                     if let Some(WebSocketState::Closed(mut stream)) =
                                         token_to_websockets.remove(&token)
                                     {
-                                        if let Err(e) = poll.registry().deregister(stream.get_mut())
-                                        {
-                                            eprintln!(
-                                                "failed to deregister stream for token {token:?}: {e:?}"
-                                            );
+                                        if let Err(e) = poll.registry().deregister(&mut stream) {
                                             warn!(
                                                 "failed to deregister stream for token {:?}: {:?}",
                                                 token, e
@@ -535,21 +1844,499 @@ impl Server {
                                         }
                                     }
                                 }
+
+                                if let Some((stream, response)) =
+                                    token_to_http_responses.get_mut(&token)
+                                {
+                                    if !matches!(response.write_to(stream), Ok(false)) {
+                                        if let Some((mut stream, _)) =
+                                            token_to_http_responses.remove(&token)
+                                        {
+                                            if let Err(e) = poll.registry().deregister(&mut stream)
+                                            {
+                                                warn!("failed to deregister stream for token {:?}: {:?}", token, e);
+                                            }
+                                        }
+                                    }
+                                }
                             }
                         }
                     }
                 }
+
+                let now = Instant::now();
+                for timer in timers.expire(now) {
+                    let token = match timer {
+                        Timer::Request(token) => {
+                            // The connections that sent their request are no
+                            // longer pending
+                            if let Some(mut pending) = token_to_pending_connections.remove(&token) {
+                                debug!(
+                                    "closing connection that did not send its request within {:?}",
+                                    request_timeout
+                                );
+                                if let Err(e) = poll.registry().deregister(&mut pending.stream) {
+                                    warn!(
+                                        "failed to deregister stream for token {:?}: {:?}",
+                                        token, e
+                                    );
+                                }
+                            }
+                            continue;
+                        }
+                        Timer::KeepAlive(token) => token,
+                    };
+                    // The timers of closed connections are left to expire
+                    let Some(state) = token_to_websockets.get_mut(&token) else {
+                        continue;
+                    };
+                    if let Err(e) = state.next_state(WebSocketMessage::KeepAlive(now)) {
+                        warn!(
+                            "failed to keep WebSocket with token {:?} alive: {:?}",
+                            token, e
+                        );
+                    }
+
+                    if let WebSocketState::Closed(_) = state {
+                        if let Some(WebSocketState::Closed(mut stream)) =
+                            token_to_websockets.remove(&token)
+                        {
+                            if let Err(e) = poll.registry().deregister(&mut stream) {
+                                warn!("failed to deregister stream for token {:?}: {:?}", token, e);
+                            }
+                        }
+                    } else if let Some(deadline) = state.keepalive_deadline() {
+                        timers.schedule(deadline, Timer::KeepAlive(token));
+                    }
+                }
             }
         });
 
-        ServerStarted { sender }
+        Ok(ServerStarted {
+            sender,
+            commands,
+            thread,
+        })
     }
 }
 
 impl ServerStarted {
-    pub fn send_message(&self, message: Arc<str>) {
-        self.sender.send(message.clone()).unwrap_or_else(|e| {
-            panic!("failed to send text `{message}` to WebSocket clients: {e:?}")
-        });
+    /// Broadcasts `event` to the clients subscribed to it, without waiting for
+    /// the server. The oldest events not broadcast yet are dropped if the
+    /// server falls behind. Fails if the server stopped, see
+    /// [`ServerStarted::stopped`]
+    pub fn send_event(&self, event: Event) -> Result<(), crate::Error> {
+        self.sender
+            .send(event)
+            .map_err(|_| crate::Error::ServerStopped)
+    }
+
+    /// A sender for the events to broadcast, for use by the mpv connection
+    pub fn sender(&self) -> SyncSender<Event> {
+        self.sender.clone()
+    }
+
+    /// The commands sent by WebSocket clients, to be forwarded to mpv
+    pub fn commands(&mut self) -> &mut mio_channel::Receiver<Command> {
+        &mut self.commands
+    }
+
+    /// Waits for the server thread to stop and returns the error that stopped
+    /// it
+    pub fn stopped(self) -> crate::Error {
+        drop(self.sender);
+        match self.thread.join() {
+            Ok(Err(error)) => error,
+            // The panic is reported by the panic hook
+            Ok(Ok(())) | Err(_) => crate::Error::ServerStopped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::time::Duration;
+
+    use super::*;
+
+    fn unused_address() -> SocketAddr {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("should find an unused port")
+    }
+
+    #[test]
+    fn subtitle_burst_arrives_complete_and_in_order() {
+        let address = unused_address();
+        let server = Server::new(address).start().expect("server should start");
+
+        let stream = std::net::TcpStream::connect(address).expect("should connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mut websocket, _) = tungstenite::client(format!("ws://{address}/"), stream)
+            .expect("handshake should succeed");
+        // The server registers the client on its next wakeup
+        thread::sleep(Duration::from_millis(100));
+
+        let lines = (0..500)
+            .map(|line| format!("karaoke line {line}"))
+            .collect::<Vec<_>>();
+        for line in &lines {
+            server
+                .send_event(Event::Subtitle {
+                    property: Property::SubText,
+                    text: line.clone(),
+                    sub_start: None,
+                    sub_end: None,
+                    time_pos: None,
+                })
+                .expect("server should be running");
+        }
+
+        let received = (0..lines.len())
+            .map(
+                |_| match websocket.read().expect("should receive every line") {
+                    tungstenite::Message::Text(text) => text.as_str().to_owned(),
+                    message => panic!("unexpected message {message:?}"),
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(received, lines);
+    }
+
+    #[test]
+    fn deadline_clamps_huge_timeouts() {
+        let now = Instant::now();
+
+        assert_eq!(
+            deadline(now, Duration::from_secs(1)),
+            now + Duration::from_secs(1)
+        );
+        assert_eq!(deadline(now, Duration::MAX), now + MAX_TIMEOUT);
+    }
+
+    #[test]
+    fn server_accepts_huge_timeouts() {
+        let address = unused_address();
+        let server = Server::new(address)
+            .keepalive(Duration::MAX, Duration::MAX)
+            .request_timeout(Duration::MAX)
+            .start()
+            .expect("server should start");
+
+        let stream = std::net::TcpStream::connect(address).expect("should connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mut websocket, _) = tungstenite::client(format!("ws://{address}/"), stream)
+            .expect("handshake should succeed");
+        // The server registers the client on its next wakeup
+        thread::sleep(Duration::from_millis(100));
+
+        server
+            .send_event(subtitle("line"))
+            .expect("server should be running");
+        match websocket.read().expect("should receive the line") {
+            tungstenite::Message::Text(text) => assert_eq!(text.as_str(), "line"),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    fn subtitle(text: &str) -> Event {
+        Event::Subtitle {
+            property: Property::SubText,
+            text: text.to_owned(),
+            sub_start: None,
+            sub_end: None,
+            time_pos: None,
+        }
+    }
+
+    #[test]
+    fn history_keeps_the_last_lines_up_to_its_capacity() {
+        let mut history = History::new(2);
+        for text in ["one", "two", "three"] {
+            history.push(&subtitle(text), &Arc::from(text));
+        }
+
+        let lines = history
+            .lines
+            .iter()
+            .map(|(_, msg)| msg.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["two", "three"]);
+    }
+
+    #[test]
+    fn history_does_not_allocate_a_huge_capacity_upfront() {
+        let mut history = History::new(usize::MAX);
+        history.push(&subtitle("line"), &Arc::from("line"));
+
+        assert_eq!(history.lines.len(), 1);
+    }
+
+    fn handshake_request(uri: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    fn send_queue(limit: usize, policy: SlowClientPolicy) -> SendQueue {
+        SendQueue::new(Token(0), NonZeroUsize::new(limit).unwrap(), policy)
+    }
+
+    fn drain(queue: &mut SendQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop_front())
+            .map(|text| text.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn send_queue_keeps_everything_under_its_limit() {
+        let mut queue = send_queue(3, SlowClientPolicy::Disconnect);
+        assert!(queue.push("sub 1".into(), true));
+        assert!(queue.push("reply".into(), false));
+        assert!(queue.push("sub 2".into(), true));
+        assert_eq!(queue.dropped, 0);
+        assert_eq!(drain(&mut queue), ["sub 1", "reply", "sub 2"]);
+    }
+
+    #[test]
+    fn send_queue_disconnects_a_full_client() {
+        let mut queue = send_queue(2, SlowClientPolicy::Disconnect);
+        assert!(queue.push("sub 1".into(), true));
+        assert!(queue.push("reply".into(), false));
+        assert!(!queue.push("sub 2".into(), true));
+        assert_eq!(queue.dropped, 0);
+    }
+
+    #[test]
+    fn send_queue_drops_the_oldest_messages() {
+        let mut queue = send_queue(3, SlowClientPolicy::DropOldest);
+        assert!(queue.push("sub 1".into(), true));
+        assert!(queue.push("reply".into(), false));
+        assert!(queue.push("sub 2".into(), true));
+        assert!(queue.push("sub 3".into(), true));
+        assert!(queue.push("connected".into(), false));
+        assert_eq!(queue.dropped, 2);
+        assert!(queue.dropping);
+        assert_eq!(drain(&mut queue), ["sub 2", "sub 3", "connected"]);
+        assert!(!queue.dropping);
+    }
+
+    #[test]
+    fn send_queue_drops_the_newest_messages() {
+        let mut queue = send_queue(3, SlowClientPolicy::DropNewest);
+        assert!(queue.push("sub 1".into(), true));
+        assert!(queue.push("reply".into(), false));
+        assert!(queue.push("sub 2".into(), true));
+        assert!(queue.push("sub 3".into(), true));
+        assert!(queue.push("connected".into(), false));
+        assert_eq!(queue.dropped, 2);
+        assert_eq!(drain(&mut queue), ["sub 1", "reply", "sub 2"]);
+    }
+
+    #[test]
+    fn send_queue_coalesces_subtitles_into_the_latest() {
+        let mut queue = send_queue(4, SlowClientPolicy::CoalesceLatest);
+        assert!(queue.push("sub 1".into(), true));
+        assert!(queue.push("reply".into(), false));
+        assert!(queue.push("sub 2".into(), true));
+        assert!(queue.push("sub 3".into(), true));
+        // A new subtitle replaces every queued one
+        assert!(queue.push("sub 4".into(), true));
+        assert_eq!(queue.dropped, 3);
+        assert_eq!(drain(&mut queue), ["reply", "sub 4"]);
+    }
+
+    #[test]
+    fn send_queue_keeps_the_latest_subtitle_when_coalescing_other_messages() {
+        let mut queue = send_queue(3, SlowClientPolicy::CoalesceLatest);
+        assert!(queue.push("sub 1".into(), true));
+        assert!(queue.push("sub 2".into(), true));
+        assert!(queue.push("reply 1".into(), false));
+        assert!(queue.push("reply 2".into(), false));
+        assert_eq!(queue.dropped, 1);
+        assert_eq!(drain(&mut queue), ["sub 2", "reply 1", "reply 2"]);
+    }
+
+    #[test]
+    fn send_queue_drops_the_oldest_message_when_only_one_subtitle_is_queued() {
+        let mut queue = send_queue(3, SlowClientPolicy::CoalesceLatest);
+        assert!(queue.push("sub 1".into(), true));
+        assert!(queue.push("reply 1".into(), false));
+        assert!(queue.push("reply 2".into(), false));
+        assert!(queue.push("reply 3".into(), false));
+        assert_eq!(queue.dropped, 1);
+        assert!(queue.push("reply 4".into(), false));
+        assert_eq!(queue.dropped, 2);
+        assert_eq!(drain(&mut queue), ["reply 2", "reply 3", "reply 4"]);
+    }
+
+    #[test]
+    fn tokens_match_only_identical_tokens() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secreT", "secret"));
+        assert!(!tokens_match("secret", "secrets"));
+        assert!(!tokens_match("secrets", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+
+    #[test]
+    fn authenticate_accepts_the_token_in_the_query() {
+        let request = handshake_request("/?history&token=secret", &[]);
+        assert!(matches!(
+            authenticate(&request, "secret"),
+            Some(Credentials::Query)
+        ));
+    }
+
+    #[test]
+    fn authenticate_percent_decodes_the_token_in_the_query() {
+        let request = handshake_request("/?token=a%2Bb%2Fc%3D", &[]);
+        assert!(matches!(
+            authenticate(&request, "a+b/c="),
+            Some(Credentials::Query)
+        ));
+        let request = handshake_request("/?token=a+b/c=%", &[]);
+        assert!(authenticate(&request, "a+b/c=").is_none());
+    }
+
+    #[test]
+    fn authenticate_accepts_the_token_as_a_subprotocol() {
+        let request = handshake_request("/", &[("Sec-WebSocket-Protocol", "chat, secret")]);
+        assert!(matches!(
+            authenticate(&request, "secret"),
+            Some(Credentials::Protocol)
+        ));
+    }
+
+    #[test]
+    fn authenticate_accepts_the_token_as_a_bearer() {
+        let request = handshake_request("/", &[("Authorization", "Bearer secret")]);
+        assert!(matches!(
+            authenticate(&request, "secret"),
+            Some(Credentials::Authorization)
+        ));
+        let request = handshake_request("/", &[("Authorization", "Basic secret")]);
+        assert!(authenticate(&request, "secret").is_none());
+    }
+
+    #[test]
+    fn authorize_rejects_missing_or_invalid_tokens() {
+        for request in [
+            handshake_request("/", &[]),
+            handshake_request("/?token=wrong", &[]),
+            handshake_request("/?secret", &[]),
+            handshake_request("/", &[("Sec-WebSocket-Protocol", "secret2")]),
+            handshake_request("/", &[("Authorization", "Bearer secre")]),
+        ] {
+            let response = authorize(&request, Some("secret"), &[])
+                .err()
+                .expect("request should be rejected");
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+
+    #[test]
+    fn authorize_accepts_everyone_without_a_token() {
+        assert!(matches!(
+            authorize(&handshake_request("/", &[]), None, &[]),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn origin_matches_exact_origins() {
+        assert!(origin_matches("https://example.com", "https://example.com"));
+        assert!(!origin_matches("https://example.com", "http://example.com"));
+        assert!(!origin_matches(
+            "https://example.com",
+            "https://example.com:8080"
+        ));
+        assert!(!origin_matches("https://example.com", "https://example.co"));
+    }
+
+    #[test]
+    fn origin_matches_ignores_case() {
+        assert!(origin_matches("https://Example.COM", "HTTPS://example.com"));
+        assert!(origin_matches(
+            "https://*.EXAMPLE.com",
+            "https://Sub.Example.Com"
+        ));
+    }
+
+    #[test]
+    fn origin_matches_any_origin_with_a_lone_wildcard() {
+        assert!(origin_matches("*", "https://example.com"));
+        assert!(origin_matches("*", "null"));
+        assert!(origin_matches("*", ""));
+    }
+
+    #[test]
+    fn origin_matches_any_port() {
+        assert!(origin_matches(
+            "http://localhost:*",
+            "http://localhost:8080"
+        ));
+        assert!(origin_matches("http://localhost:*", "http://LOCALHOST:1"));
+        assert!(!origin_matches("http://localhost:*", "http://localhost"));
+        assert!(!origin_matches(
+            "http://localhost:*",
+            "https://localhost:8080"
+        ));
+        assert!(!origin_matches(
+            "http://localhost:*",
+            "http://localhost.example.com:80"
+        ));
+    }
+
+    #[test]
+    fn origin_matches_any_subdomain() {
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://a.example.com"
+        ));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "http://a.example.com"
+        ));
+    }
+
+    #[test]
+    fn origin_matches_rejects_near_misses() {
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://evilexample.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com.evil.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://a.example.com:8080"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://a.example.community"
+        ));
+        assert!(!origin_matches(
+            "https://example.com",
+            "https://example.com.evil.com"
+        ));
     }
 }